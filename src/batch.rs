@@ -0,0 +1,194 @@
+use std::io::Cursor;
+
+use hidapi::{DeviceInfo, HidApi};
+
+use crate::devices::{Bootloader, Devices, Keyboard};
+use crate::error::{Error, ErrorKind};
+use crate::firmware::kind::{self, Kind};
+use crate::firmware::offset;
+use crate::firmware::{Firmware, UnsafeFirmware};
+use crate::flash::FlashingOptions;
+use crate::progress::{Event, Observer};
+use crate::Result;
+
+/// How flashing a single keyboard of a batch went
+pub struct Outcome {
+    pub label: String,
+    pub result: Result<()>,
+}
+
+/// Prints a line for every phase and every 10% of progress, prefixed with the device label
+pub struct LabeledProgress<'l> {
+    label: &'l str,
+    decile: usize,
+}
+
+impl<'l> LabeledProgress<'l> {
+    pub fn new(label: &'l str) -> Self {
+        Self { label, decile: 0 }
+    }
+}
+
+impl Observer for LabeledProgress<'_> {
+    fn notify(&mut self, event: &Event) {
+        match event {
+            Event::Init => println!("[{}] initialized", self.label),
+            Event::Prepare { offset, blocks } => {
+                println!(
+                    "[{}] writing {} blocks at {:#x}",
+                    self.label, blocks, offset
+                )
+            }
+            Event::Chunk(progress) => {
+                let decile = (progress.fraction() * 10.0) as usize;
+                if decile > self.decile {
+                    self.decile = decile;
+                    println!(
+                        "[{}] {:>3}% {:.1} KiB/s",
+                        self.label,
+                        decile * 10,
+                        progress.throughput() / 1024.0
+                    );
+                }
+            }
+            Event::Reboot => println!("[{}] rebooting", self.label),
+        }
+    }
+}
+
+/// What applies to every keyboard of a batch, as given on the command line
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BatchOptions {
+    pub offset: Option<u32>,
+    pub eeprom_size: Option<u32>,
+    pub force_offset: bool,
+    /// The user has to have confirmed this already
+    pub overwrite_bootloader: bool,
+    pub wipe_eeprom: bool,
+}
+
+/// How one device of a batch gets flashed, before any of the checks
+pub fn options(
+    info: &DeviceInfo,
+    kind: Kind,
+    base: Option<u32>,
+    batch: BatchOptions,
+) -> Result<FlashingOptions> {
+    let mut options = FlashingOptions::try_from(info)?;
+    options
+        .with_offset(batch.offset)
+        .with_kind(kind)
+        .with_eeprom_size(batch.eeprom_size)
+        .with_wipe_eeprom(batch.wipe_eeprom)
+        .with_base(base)?;
+    Ok(options)
+}
+
+/// Flash `firmware` to every device at the same time, one thread per device
+///
+/// A failing device doesn't stop the others, every device gets an [`Outcome`].
+pub fn flash_all(
+    devices: &Devices<Bootloader>,
+    targets: &[&DeviceInfo],
+    firmware: &UnsafeFirmware<Cursor<Vec<u8>>>,
+    batch: BatchOptions,
+) -> Result<Vec<Outcome>> {
+    let (base, dfu) = (firmware.base, firmware.dfu.as_ref());
+    let firmware = firmware.inner.get_ref().as_slice();
+    let inference = &offset::infer(firmware);
+    let kind = kind::classify(firmware);
+    // Only one HidApi can exist at a time so every device is opened up front
+    let api = HidApi::new()?;
+    let jobs = targets
+        .iter()
+        .map(|d| {
+            let keyboard = d
+                .open_device(&api)
+                .map(Keyboard::<Bootloader>::new)
+                .map_err(Error::from);
+            (devices.label(d), *d, keyboard)
+        })
+        .collect::<Vec<_>>();
+    drop(api);
+
+    let outcomes = std::thread::scope(|scope| {
+        let handles = jobs
+            .into_iter()
+            .map(|(label, info, keyboard)| {
+                let handle = scope.spawn({
+                    let label = label.clone();
+                    move || {
+                        keyboard.and_then(|mut keyboard| {
+                            // Only the bootloader is known, the suffix names the keyboard
+                            if let Some(Err(e)) = dfu.map(|suffix| {
+                                suffix.check(&[(info.vendor_id(), info.product_id())])
+                            }) {
+                                println!("[{}] warning: {}", label, e.kind);
+                            }
+                            let options = options(info, kind, base, batch)?;
+                            match options.check_inferred(inference) {
+                                Err(e) if batch.force_offset => {
+                                    println!("[{}] warning: {}", label, e.kind)
+                                }
+                                result => result?,
+                            }
+                            // Confirmed once for all devices before starting
+                            if !batch.overwrite_bootloader {
+                                options.check_protected(firmware.len())?;
+                            }
+                            options.check_eeprom(firmware.len())?;
+                            let firmware = Firmware::try_from((
+                                UnsafeFirmware::from(Cursor::new(firmware.to_vec())),
+                                &options,
+                            ))?;
+                            keyboard.flash_with(
+                                firmware,
+                                options,
+                                &mut LabeledProgress::new(&label),
+                            )
+                        })
+                    }
+                });
+                (label, handle)
+            })
+            .collect::<Vec<_>>();
+        // A panic in one thread only fails its own device
+        handles
+            .into_iter()
+            .map(|(label, handle)| {
+                let result = handle.join().unwrap_or_else(|panic| {
+                    let message = panic
+                        .downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_default();
+                    Err(ErrorKind::Panicked(message).into())
+                });
+                Outcome { label, result }
+            })
+            .collect::<Vec<_>>()
+    });
+    Ok(outcomes)
+}
+
+pub fn print_summary(outcomes: &[Outcome]) {
+    let width = outcomes
+        .iter()
+        .map(|o| o.label.len())
+        .max()
+        .unwrap_or_default()
+        .max("device".len());
+    println!("{:<width$}  result", "device", width = width);
+    for outcome in outcomes {
+        match &outcome.result {
+            Ok(()) => println!("{:<width$}  ok", outcome.label, width = width),
+            Err(e) => println!(
+                "{:<width$}  failed: {:?} ({})",
+                outcome.label,
+                e.kind,
+                e.kind,
+                width = width
+            ),
+        }
+    }
+}
@@ -1,12 +1,20 @@
 use std::path::PathBuf;
 
-use clap::{ArgGroup, Parser, Subcommand};
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
 
 /// A command line tool to flash qmk firmware to SN32F* based keyboards
 #[derive(Parser, Clone, Debug)]
 pub struct Args {
     #[command(subcommand)]
     pub command: Command,
+    /// Only use the blocks of a UF2 file built for this family id, e.g. 0x1234abcd
+    #[arg(long, global = true, value_parser = parse_family)]
+    pub uf2_family: Option<u32>,
+}
+
+fn parse_family(s: &str) -> Result<u32, String> {
+    let hex = s.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(hex, 16).map_err(|e| e.to_string())
 }
 
 #[derive(Clone, Debug, Subcommand)]
@@ -38,48 +46,152 @@ pub enum Command {
     Flash {
         /// The path to the firmware file
         firmware: PathBuf,
-        /// The path to the keyboard
+        /// The keyboard to flash: vid:pid, vid:pid#N, path=…, serial=…, port=… or a chip name
         #[arg(short, long)]
         keyboard: Option<String>,
         /// The offset to flash from
         #[arg(short, long)]
         offset: Option<u32>,
+        /// Also flash this jumploader to 0x0 and the firmware to 0x200, in one go
+        #[arg(short, long, conflicts_with_all = ["offset", "all"])]
+        jumploader: Option<PathBuf>,
+        /// Flash even if the vector table says the firmware was linked for another offset
+        #[arg(long)]
+        force_offset: bool,
+        /// Allow writing over the bootloader, asks for confirmation. This can brick the keyboard
+        #[arg(long)]
+        overwrite_bootloader: bool,
+        /// Erase the emulated EEPROM too, this resets the keymap and VIA settings
+        #[arg(long)]
+        wipe_eeprom: bool,
+        /// The size of the emulated EEPROM at the end of flash, if the keyboard doesn't use the
+        /// chip's default
+        #[arg(long)]
+        eeprom_size: Option<u32>,
+        /// Flash every keyboard in bootloader mode (that matches --keyboard) at the same time
+        #[arg(short, long, conflicts_with = "verify")]
+        all: bool,
+        /// The reboot sequence used when the keyboard is in normal mode
+        #[arg(short, long, value_enum, default_value = "auto")]
+        method: RebootMethod,
+        /// Wait for the keyboard to come back in normal mode after flashing
+        #[arg(long)]
+        verify: bool,
+        /// The vid:pid the keyboard should come back with, defaults to the one in the firmware or
+        /// else any known keyboard
+        #[arg(long, requires = "verify")]
+        expect: Option<String>,
+        /// How many seconds to wait for the keyboard to come back
+        #[arg(long, default_value_t = 10, requires = "verify")]
+        verify_timeout: u64,
+    },
+    /// Print the flash map of a chip and where a firmware would go
+    Layout {
+        /// The firmware to place, without one only the chip's regions are shown
+        firmware: Option<PathBuf>,
+        /// Only show this chip instead of every supported one
+        #[arg(short, long, value_enum)]
+        chip: Option<Chip>,
+        /// The offset the firmware goes to, defaults to the one for its kind on the chip
+        #[arg(short, long)]
+        offset: Option<u32>,
+        /// The size of the emulated EEPROM at the end of flash, if the keyboard doesn't use the
+        /// chip's default
+        #[arg(long)]
+        eeprom_size: Option<u32>,
+        /// How to print the map
+        #[arg(short, long, value_enum, default_value = "text")]
+        format: OutputFormat,
     },
     /// Reboot the keyboard
     Reboot {
-        /// The identifier for the keyboard
+        /// The keyboard to reboot: vid:pid, vid:pid#N, path=…, serial=…, port=… or a chip name
         #[arg(short, long)]
         keyboard: Option<String>,
-        #[command(subcommand)]
-        bootloader: Bootloader,
-        // normal: bool,
+        /// The mode to reboot into
+        #[arg(short, long, value_enum)]
+        to: RebootTarget,
+        /// The reboot sequence used to get into bootloader mode
+        #[arg(short, long, value_enum, default_value = "auto")]
+        method: RebootMethod,
+    },
+    /// Create a virtual keyboard through /dev/uhid that other sflasher commands can talk to
+    Simulate {
+        /// The chip the simulated bootloader pretends to be
+        #[arg(short, long, value_enum, default_value = "sn32f260")]
+        chip: Chip,
+        /// The vid:pid the keyboard uses in normal mode
+        #[arg(short, long)]
+        device: Option<String>,
+        /// Start in normal mode instead of bootloader mode
+        #[arg(short, long)]
+        normal: bool,
+        /// Write the simulated flash to this file every time the bootloader reboots
+        #[arg(long)]
+        dump: Option<PathBuf>,
     },
 }
 
 #[derive(Clone, Debug, Subcommand)]
 pub enum FirmwareCommand {
-    Check { path: PathBuf },
+    Check {
+        path: PathBuf,
+        /// Check against this chip instead of accepting any supported chip
+        #[arg(short, long, value_enum, conflicts_with = "keyboard")]
+        chip: Option<Chip>,
+        /// Check against the chip of this keyboard in bootloader mode
+        #[arg(short, long)]
+        keyboard: Option<String>,
+        /// How to print the findings
+        #[arg(short, long, value_enum, default_value = "text")]
+        format: OutputFormat,
+        /// Fail on warnings too
+        #[arg(long)]
+        strict: bool,
+        /// The size of the emulated EEPROM at the end of flash, if the keyboard doesn't use the
+        /// chip's default
+        #[arg(long)]
+        eeprom_size: Option<u32>,
+    },
+    /// Print the size, vector table, hashes and embedded strings of a firmware file
+    Info {
+        path: PathBuf,
+        /// How to print the information
+        #[arg(short, long, value_enum, default_value = "text")]
+        format: OutputFormat,
+    },
 }
 
-#[derive(Clone, Debug, Subcommand)]
-pub enum Bootloader {
-    EVision,
-    Hfd,
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
 }
 
-impl Bootloader {
-    pub fn commands(&self) -> [u8; 8] {
-        let mut res = [0; 8];
-        match self {
-            Bootloader::EVision => {
-                res[..4].copy_from_slice(&0x5AA555AA_u32.to_le_bytes());
-                res[4..].copy_from_slice(&0xCC3300FF_u32.to_le_bytes());
-            }
-            Bootloader::Hfd => {
-                res[..4].copy_from_slice(&0x5A8942AA_u32.to_le_bytes());
-                res[4..].copy_from_slice(&0xCC6271FF_u32.to_le_bytes());
-            }
-        }
-        res
-    }
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum Chip {
+    #[value(name = "sn32f248")]
+    SN32F248,
+    #[value(name = "sn32f248b")]
+    SN32F248B,
+    /// Sold as SN32F268F
+    #[value(name = "sn32f260", alias = "sn32f268f")]
+    SN32F260,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum RebootTarget {
+    /// Reboot a keyboard in normal mode into its bootloader
+    Bootloader,
+    /// Reboot a keyboard in bootloader mode back into its firmware
+    Normal,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum RebootMethod {
+    #[value(name = "evision")]
+    EVision,
+    Hfd,
+    /// Try every known sequence until the bootloader shows up
+    Auto,
 }
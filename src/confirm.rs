@@ -0,0 +1,27 @@
+use std::io::{BufRead, IsTerminal, Write};
+
+use crate::error::ErrorKind;
+use crate::Result;
+
+pub const BOOTLOADER_RISK: &str = "\
+The bootloader is what lets sflasher talk to the keyboard at all. If it is overwritten and the new
+firmware doesn't work, the keyboard no longer shows up in bootloader mode and can only be recovered
+with an SWD programmer. Only continue if the image contains a working bootloader or jumploader and
+you have a way to recover the chip.";
+
+/// Ask the user to type `yes`, fails if nobody is there to answer
+pub fn confirm(question: &str) -> Result<()> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Err(ErrorKind::NotConfirmed.into());
+    }
+    eprint!("{} Type yes to continue: ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer)?;
+    if answer.trim() == "yes" {
+        Ok(())
+    } else {
+        Err(ErrorKind::NotConfirmed.into())
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::cli::RebootMethod;
+use crate::devices::{parse_vid_pid, DeviceEntry, DeviceMap};
+use crate::error::ErrorKind;
+use crate::flash::Sonix;
+use crate::Result;
+
+/// Every file in here is loaded in alphabetical order
+const SYSTEM_DIR: &str = "/etc/sflasher/devices.d";
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum DatabaseError {
+    /// Malformed files, unknown fields and values of the wrong type, as reported by the parser
+    #[error("{0}")]
+    Syntax(String),
+    #[error("\"{0}\" is not a vid:pid like \"0c45:652f\"")]
+    InvalidId(String),
+    #[error("{0}: name is empty")]
+    MissingName(String),
+    #[error("{id}: unknown chip \"{chip}\", expected sn32f248, sn32f248b or sn32f260")]
+    UnknownChip { id: String, chip: String },
+    #[error("{id}: unknown reboot method \"{method}\", expected evision, hfd or auto")]
+    UnknownMethod { id: String, method: String },
+    #[error("{id}: an EEPROM of {size} bytes does not fit into the flash of the {chip:?}")]
+    InvalidEepromSize { id: String, size: u32, chip: Sonix },
+}
+
+/// An entry as it is written in a database file, see [`DeviceEntry`]
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct RawEntry {
+    name: String,
+    chip: Option<String>,
+    method: Option<String>,
+    notes: Option<String>,
+    eeprom_size: Option<u32>,
+}
+
+/// A database file is a table of entries keyed by "vid:pid"
+type RawDatabase = BTreeMap<String, RawEntry>;
+
+/// The database files in the order they are applied, later ones override earlier ones
+///
+/// System files come from `/etc/sflasher/devices.d/*.toml` and `*.json`, the user's from
+/// `$XDG_CONFIG_HOME/sflasher/devices.toml` or `.json`, `~/.config` without `XDG_CONFIG_HOME`.
+pub fn paths() -> Vec<PathBuf> {
+    let mut paths = std::fs::read_dir(SYSTEM_DIR)
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| format_of(path).is_some())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    paths.sort();
+
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    if let Some(config) = config {
+        let dir = config.join("sflasher");
+        paths.push(dir.join("devices.toml"));
+        paths.push(dir.join("devices.json"));
+    }
+    paths.retain(|path| path.is_file());
+    paths
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileFormat {
+    Toml,
+    Json,
+}
+
+fn format_of(path: &Path) -> Option<FileFormat> {
+    match path.extension()?.to_str()? {
+        "toml" => Some(FileFormat::Toml),
+        "json" => Some(FileFormat::Json),
+        _ => None,
+    }
+}
+
+/// The built-in keyboards with every database file applied on top
+pub fn load(mut devices: DeviceMap) -> Result<DeviceMap> {
+    for path in paths() {
+        let text = std::fs::read_to_string(&path)?;
+        let format = format_of(&path).unwrap_or(FileFormat::Toml);
+        let entries = parse(&text, format).map_err(|error| ErrorKind::InvalidDatabase {
+            path: path.display().to_string(),
+            error: Box::new(error),
+        })?;
+        devices.extend(entries);
+    }
+    Ok(devices)
+}
+
+/// Parse and validate a database file
+pub fn parse(text: &str, format: FileFormat) -> Result<DeviceMap, DatabaseError> {
+    let raw: RawDatabase = match format {
+        FileFormat::Toml => toml::from_str(text).map_err(|e| e.to_string().trim_end().to_owned()),
+        FileFormat::Json => serde_json::from_str(text).map_err(|e| e.to_string()),
+    }
+    .map_err(DatabaseError::Syntax)?;
+    raw.into_iter()
+        .map(|(id, raw)| {
+            let key = parse_vid_pid(&id).map_err(|_| DatabaseError::InvalidId(id.clone()))?;
+            Ok((key, entry(&id, raw)?))
+        })
+        .collect()
+}
+
+fn entry(id: &str, raw: RawEntry) -> Result<DeviceEntry, DatabaseError> {
+    if raw.name.is_empty() {
+        return Err(DatabaseError::MissingName(id.into()));
+    }
+    let chip = raw
+        .chip
+        .map(|chip| {
+            chip.parse::<Sonix>()
+                .map_err(|_| DatabaseError::UnknownChip {
+                    id: id.into(),
+                    chip,
+                })
+        })
+        .transpose()?;
+    let method = raw
+        .method
+        .map(|method| {
+            RebootMethod::from_str(&method, true).map_err(|_| DatabaseError::UnknownMethod {
+                id: id.into(),
+                method,
+            })
+        })
+        .transpose()?;
+
+    // Without a chip the size has to fit into every one of them
+    if let Some(size) = raw.eeprom_size {
+        let chips = chip.map_or(Sonix::all().to_vec(), |chip| vec![chip]);
+        for chip in chips {
+            if size == 0 || size > chip.flash_size() as u32 - chip.offset() {
+                return Err(DatabaseError::InvalidEepromSize {
+                    id: id.into(),
+                    size,
+                    chip,
+                });
+            }
+        }
+    }
+    Ok(DeviceEntry {
+        name: raw.name,
+        chip,
+        method,
+        notes: raw.notes,
+        eeprom_size: raw.eeprom_size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn only(text: &str, format: FileFormat) -> DeviceEntry {
+        let mut devices = parse(text, format).unwrap();
+        assert_eq!(devices.len(), 1);
+        devices.remove(&(0x0c45, 0x652f)).unwrap()
+    }
+
+    #[test]
+    fn toml() {
+        let tables = only(
+            r#"
+            ["0c45:652f"]
+            name = "Glorious GMMK"
+            chip = "sn32f248b"
+            method = "evision"
+            notes = "hold fn+esc"
+            eeprom_size = 0x400
+            "#,
+            FileFormat::Toml,
+        );
+        assert_eq!(tables.chip, Some(Sonix::SN32F248B));
+        assert_eq!(tables.method, Some(RebootMethod::EVision));
+        assert_eq!(tables.eeprom_size, Some(0x400));
+
+        let inline = only(
+            r#""0c45:652f" = { name = "Glorious GMMK", chip = "sn32f248b" }"#,
+            FileFormat::Toml,
+        );
+        let dotted = only(
+            "\"0c45:652f\".name = \"Glorious GMMK\"\n\"0c45:652f\".chip = \"SN32F248B\"",
+            FileFormat::Toml,
+        );
+        assert_eq!(inline, dotted);
+        assert_eq!(inline.name, "Glorious GMMK");
+        assert_eq!(inline.chip, Some(Sonix::SN32F248B));
+    }
+
+    #[test]
+    fn json() {
+        let entry = only(
+            r#"{"0c45:652f": {"name": "GMMK \ud83d\ude00", "chip": "sn32f268f"}}"#,
+            FileFormat::Json,
+        );
+        assert_eq!(entry.name, "GMMK \u{1F600}");
+        assert_eq!(entry.chip, Some(Sonix::SN32F260));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text| parse(text, FileFormat::Toml).unwrap_err();
+        let DatabaseError::Syntax(message) = error("[\"0c45:652f\"]\nname = \"x\"\nnmae = \"x\"")
+        else {
+            panic!("not a syntax error")
+        };
+        assert!(message.contains("unknown field `nmae`"), "{}", message);
+        let DatabaseError::Syntax(message) = error("[\"0c45:652f\"]\nchip = \"sn32f260\"") else {
+            panic!("not a syntax error")
+        };
+        assert!(message.contains("missing field `name`"), "{}", message);
+
+        assert_eq!(
+            error("[\"0c45\"]\nname = \"x\""),
+            DatabaseError::InvalidId("0c45".into())
+        );
+        assert_eq!(
+            error("[\"0c45:652f\"]\nname = \"\""),
+            DatabaseError::MissingName("0c45:652f".into())
+        );
+        assert_eq!(
+            error("[\"0c45:652f\"]\nname = \"x\"\nchip = \"sn32f240\""),
+            DatabaseError::UnknownChip {
+                id: "0c45:652f".into(),
+                chip: "sn32f240".into()
+            }
+        );
+        assert_eq!(
+            error("[\"0c45:652f\"]\nname = \"x\"\nmethod = \"magic\""),
+            DatabaseError::UnknownMethod {
+                id: "0c45:652f".into(),
+                method: "magic".into()
+            }
+        );
+        assert_eq!(
+            error("[\"0c45:652f\"]\nname = \"x\"\nchip = \"sn32f260\"\neeprom_size = 0x8000"),
+            DatabaseError::InvalidEepromSize {
+                id: "0c45:652f".into(),
+                size: 0x8000,
+                chip: Sonix::SN32F260
+            }
+        );
+    }
+
+    #[test]
+    fn deep_json() {
+        let text = "[".repeat(10_000) + &"]".repeat(10_000);
+        assert!(matches!(
+            parse(&text, FileFormat::Json),
+            Err(DatabaseError::Syntax(_))
+        ));
+    }
+}
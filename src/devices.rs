@@ -1,37 +1,126 @@
-use crate::constants::{CMD_INIT, CMD_PREPARE, CMD_REBOOT, EXPECTED_STATUS};
+use crate::cli::RebootMethod;
+use crate::constants::{BLOCK_SIZE, CMD_INIT, CMD_PREPARE, CMD_REBOOT, EXPECTED_STATUS, SONIX_VID};
 use crate::error::ErrorKind;
 use crate::firmware::Firmware;
-use crate::flash::FlashingOptions;
+use crate::flash::{FlashingOptions, Sonix};
+use crate::progress::{Event, Observer, Progress};
+use crate::reboot::Sequence;
+use crate::selector::Selector;
 use crate::traits::buffer::SizedBuffer;
 // use crate::traits::empty::EmptyOrElse;
 use crate::traits::hex::FromHex;
+use crate::traits::transport::Transport;
 use crate::Result;
 use hidapi::{DeviceInfo, HidApi, HidDevice};
 use itertools::Itertools;
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
+use std::time::Instant;
 // use std::io::{BufReader, Read};
 
+/// What sflasher knows about a vid:pid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceEntry {
+    pub name: String,
+    /// The chip inside the keyboard, if known
+    pub chip: Option<Sonix>,
+    /// The reboot sequence the keyboard understands, `--method auto` uses it
+    pub method: Option<RebootMethod>,
+    pub notes: Option<String>,
+    /// The size of the emulated EEPROM if it isn't the chip's default
+    pub eeprom_size: Option<u32>,
+}
+
+impl DeviceEntry {
+    pub fn named(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            chip: None,
+            method: None,
+            notes: None,
+            eeprom_size: None,
+        }
+    }
+}
+
+/// Everything known about the keyboards of one mode, by vid:pid
+pub type DeviceMap = HashMap<(u16, u16), DeviceEntry>;
+
 macro_rules! devices {
     ($map: expr, $(($vid:literal, $pid:literal): $name:expr),* $(,)?) => {
         $(
-                $map.insert(($vid, $pid), $name);
+                $map.insert(($vid, $pid), DeviceEntry::named($name));
         )*
     };
 }
 
-static BOOTLOADER_DEVICES: Lazy<HashMap<(u16, u16), &'static str>> = Lazy::new(|| {
-    let mut m = HashMap::new();
-    devices!(m,
-        (0x0c45, 0x7010): "SN32F268F (bootloader)",  // 0x200
-        (0x0c45, 0x7040): "SN32F248B (bootloader)",  // 0x0
-        (0x0c45, 0x7900): "SN32F248 (bootloader)",   // 0x0
-    );
-    m
+static BOOTLOADER_DEVICES: Lazy<DeviceMap> = Lazy::new(|| {
+    Sonix::all()
+        .into_iter()
+        .map(|chip| {
+            let entry = DeviceEntry {
+                chip: Some(chip),
+                ..DeviceEntry::named(chip.name())
+            };
+            ((SONIX_VID, chip.pid()), entry)
+        })
+        .collect()
 });
 
-static NORMAL_DEVICES: Lazy<HashMap<(u16, u16), &'static str>> = Lazy::new(|| {
+/// The built-in keyboards, with the device database on top once [`load_database`] ran
+static NORMAL_DEVICES: OnceCell<DeviceMap> = OnceCell::new();
+
+/// Apply the user and system device databases, see [`crate::database::paths`]
+///
+/// Has to run before the first lookup of a normal mode keyboard, later calls change nothing.
+pub fn load_database() -> Result<()> {
+    let devices = crate::database::load(builtin_devices())?;
+    // Already initialized means it's too late, the built-ins stay
+    let _ = NORMAL_DEVICES.set(devices);
+    Ok(())
+}
+
+impl RebootMethod {
+    pub fn sequences(&self) -> &'static [Sequence] {
+        match self {
+            RebootMethod::EVision => &[Sequence::EVision],
+            RebootMethod::Hfd => &[Sequence::Hfd],
+            RebootMethod::Auto => &Sequence::ALL,
+        }
+    }
+}
+
+/// The reboot method for a keyboard in normal mode, `auto` is the one its entry names if any
+pub fn reboot_method(method: RebootMethod, device: &DeviceInfo) -> RebootMethod {
+    match method {
+        RebootMethod::Auto => Normal::devices()
+            .get(&(device.vendor_id(), device.product_id()))
+            .and_then(|entry| entry.method)
+            .unwrap_or(method),
+        method => method,
+    }
+}
+
+/// The keyboards that might have `chip` and use an EEPROM size other than its default
+///
+/// A keyboard in bootloader mode can't be told apart from any other with the same chip, these
+/// are the ones whose EEPROM size would be wrong.
+pub fn custom_eeprom_sizes(chip: Sonix) -> Vec<&'static DeviceEntry> {
+    let mut entries = Normal::devices()
+        .values()
+        .filter(|entry| entry.chip.is_none_or(|c| c == chip))
+        .filter(|entry| {
+            entry
+                .eeprom_size
+                .is_some_and(|size| size != chip.eeprom_size())
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    entries
+}
+
+fn builtin_devices() -> DeviceMap {
     let mut m = HashMap::new();
     devices!(m,
         (0x05ac, 0x024f): "Apple Keyboard / Keychron / Flashquark Horizon Z",
@@ -114,7 +203,7 @@ static NORMAL_DEVICES: Lazy<HashMap<(u16, u16), &'static str>> = Lazy::new(|| {
         (0x3434, 0xfe3f): "Keychron C16 ISO",
     );
     m
-});
+}
 
 #[derive(Debug, Clone)]
 pub enum Normal {}
@@ -123,11 +212,11 @@ pub enum Bootloader {}
 
 impl Bootloader {}
 pub trait Mode {
-    fn devices() -> &'static HashMap<(u16, u16), &'static str>;
+    fn devices() -> &'static DeviceMap;
     fn mode() -> &'static str;
 }
 impl Mode for Bootloader {
-    fn devices() -> &'static HashMap<(u16, u16), &'static str> {
+    fn devices() -> &'static DeviceMap {
         &BOOTLOADER_DEVICES
     }
     fn mode() -> &'static str {
@@ -135,8 +224,8 @@ impl Mode for Bootloader {
     }
 }
 impl Mode for Normal {
-    fn devices() -> &'static HashMap<(u16, u16), &'static str> {
-        &NORMAL_DEVICES
+    fn devices() -> &'static DeviceMap {
+        NORMAL_DEVICES.get_or_init(builtin_devices)
     }
     fn mode() -> &'static str {
         "normal"
@@ -187,17 +276,22 @@ impl<T: Mode> Debug for Devices<T> {
 impl<T: Mode> Display for Devices<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         for device in &self.devices {
-            let name = match T::devices().get(&(device.vendor_id(), device.product_id())) {
-                Some(name) => name,
+            let entry = T::devices().get(&(device.vendor_id(), device.product_id()));
+            let name = match entry {
+                Some(entry) => entry.name.as_str(),
                 None => "Unknown",
             };
-            writeln!(
-                f,
-                "{}: {:x}:{:x}",
-                name,
-                device.vendor_id(),
-                device.product_id()
-            )?;
+            write!(f, "{}: {}", name, self.label(device))?;
+            if let Some(port) = port(device) {
+                write!(f, " port={}", port)?;
+            }
+            if let Some(serial) = device.serial_number().filter(|s| !s.is_empty()) {
+                write!(f, " serial={}", serial)?;
+            }
+            writeln!(f, " path={}", device.path().to_string_lossy())?;
+            if let Some(notes) = entry.and_then(|entry| entry.notes.as_ref()) {
+                writeln!(f, "  {}", notes)?;
+            }
         }
         Ok(())
     }
@@ -223,7 +317,9 @@ impl<T: Mode> Devices<T> {
                     let key = (device.vendor_id(), device.product_id());
                     T::devices().contains_key(&key)
                 })
-                .unique_by(|d| (d.vendor_id(), d.product_id()))
+                // Every interface of a keyboard shows up as its own device
+                .unique_by(|d| physical_device(d))
+                .sorted_by_key(|d| physical_device(d))
                 .cloned()
                 .collect::<Vec<DeviceInfo>>(),
             // .empty_or_else(|| ErrorKind::NoDevicesFound.into())?,
@@ -239,6 +335,27 @@ impl<T: Mode> Devices<T> {
         Ok(device)
     }
 
+    /// `vid:pid#N` where N is the index among devices with the same vid:pid
+    pub fn label(&self, device: &DeviceInfo) -> String {
+        let key = (device.vendor_id(), device.product_id());
+        let index = self
+            .devices
+            .iter()
+            .filter(|d| (d.vendor_id(), d.product_id()) == key)
+            .position(|d| d.path() == device.path())
+            .unwrap_or_default();
+        format!("{:04x}:{:04x}#{}", key.0, key.1, index)
+    }
+
+    /// All devices matching the selector
+    pub fn select(&self, selector: &Selector) -> Vec<&DeviceInfo> {
+        let matching = self.devices.iter().filter(|d| selector.matches::<T>(d));
+        match selector.index() {
+            Some(index) => matching.skip(index).take(1).collect(),
+            None => matching.collect(),
+        }
+    }
+
     pub fn decide<I: AsRef<str>>(&self, identifier: impl Into<Option<I>>) -> Result<&DeviceInfo> {
         if self.devices.is_empty() {
             Err(ErrorKind::NoDevicesFound)?;
@@ -246,17 +363,12 @@ impl<T: Mode> Devices<T> {
         let identifier = identifier.into();
         let device = match identifier {
             Some(identifier) => {
-                let identifier = identifier.as_ref();
-                let (v, p) = identifier
-                    .split(':')
-                    .take(2)
-                    .tuples()
-                    .next()
-                    .ok_or_else(|| ErrorKind::InvalidIdentifier(identifier.into()))?;
-                let vendor_id = u16::from_hex(v)?;
-                let product_id = u16::from_hex(p)?;
-
-                self.find(vendor_id, product_id)?
+                let selector = identifier.as_ref().parse::<Selector>()?;
+                match self.select(&selector)[..] {
+                    [] => return Err(ErrorKind::DeviceNotFound.into()),
+                    [device] => device,
+                    _ => return Err(ErrorKind::AmbiguousDevice(selector.to_string()).into()),
+                }
             }
             None => {
                 if self.devices.is_empty() {
@@ -272,9 +384,67 @@ impl<T: Mode> Devices<T> {
     }
 }
 
+/// Identifies the physical keyboard a hid device belongs to
+///
+/// Without a usb port the serial number is used, identical keyboards without one can't be told
+/// apart.
+fn physical_device(d: &DeviceInfo) -> (u16, u16, String) {
+    let location = port(d)
+        .or_else(|| d.serial_number().map(String::from))
+        .unwrap_or_default();
+    (d.vendor_id(), d.product_id(), location)
+}
+
+/// The usb topology (bus-port.port…) the device is plugged into, like `1-2.3`
+///
+/// Only available on linux where it is read from sysfs.
+pub fn port(info: &DeviceInfo) -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        let path = info.path().to_str().ok()?;
+        let name = std::path::Path::new(path).file_name()?;
+        let device = std::fs::canonicalize(
+            std::path::Path::new("/sys/class/hidraw")
+                .join(name)
+                .join("device"),
+        )
+        .ok()?;
+        // The closest ancestor that looks like `1-2.3` is the usb device, `1-2.3:1.0` is the
+        // interface
+        device
+            .iter()
+            .filter_map(|c| c.to_str())
+            .rfind(|c| {
+                c.split_once('-').is_some_and(|(bus, ports)| {
+                    !bus.is_empty()
+                        && bus.bytes().all(|b| b.is_ascii_digit())
+                        && !ports.is_empty()
+                        && ports.bytes().all(|b| b.is_ascii_digit() || b == b'.')
+                })
+            })
+            .map(String::from)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = info;
+        None
+    }
+}
+
+/// Parse a `vid:pid` identifier like `0c45:7040`
+pub fn parse_vid_pid(identifier: &str) -> Result<(u16, u16)> {
+    let (v, p) = identifier
+        .split(':')
+        .take(2)
+        .tuples()
+        .next()
+        .ok_or_else(|| ErrorKind::InvalidIdentifier(identifier.into()))?;
+    Ok((u16::from_hex(v)?, u16::from_hex(p)?))
+}
+
 #[derive(Debug)]
-pub struct Keyboard<Mode> {
-    device: HidDevice,
+pub struct Keyboard<Mode, T: Transport = HidDevice> {
+    device: T,
     init: bool,
     __marker: std::marker::PhantomData<Mode>,
 }
@@ -288,37 +458,31 @@ impl<Mode: self::Mode> Keyboard<Mode> {
         //     .get(&(info.vendor_id(), info.product_id()))
         //     .cloned()
         //     .unwrap_or("Unknown");
-        Ok(Self {
+        Ok(Self::new(device))
+    }
+}
+
+impl<Mode: self::Mode, T: Transport> Keyboard<Mode, T> {
+    /// Use any [`Transport`] as the keyboard instead of opening a hid device
+    pub fn new(device: T) -> Self {
+        Self {
             // name,
             device,
             init: false,
             __marker: std::marker::PhantomData,
-        })
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.device
     }
 
     fn set_feature(&mut self, report: impl AsRef<[u8]>) -> Result<()> {
-        let report = report.as_ref();
-        if report.len() > 64 {
-            return Err(ErrorKind::InvalidReportLength(report.len()).into());
-        }
-        let mut buf = [0u8; 65];
-        // add 00 at start for hidapi report id (No clue what this does)
-        // TODO: Figure out what the report id does
-        // max(report.len()) is 64, so this is safe
-        // since the buffer is 65 and the max index is 64
-        buf[1..=report.len()].copy_from_slice(report);
-        self.device.send_feature_report(&buf)?;
-        Ok(())
+        self.device.set_feature(report)
     }
 
-    // # strip 00 at start for hidapi report id
-    // return dev.get_feature_report(0, RESPONSE_LEN + 1)[1:]
     fn get_feature(&mut self) -> Result<[u8; 64]> {
-        // The report id is in buf[0] and the data is in buf[1..]
-        let mut buf = [0u8; 65];
-        self.device.get_feature_report(&mut buf)?;
-        let [_report, data @ ..] = buf;
-        Ok(data)
+        self.device.get_feature()
     }
 
     pub fn init(&mut self) -> Result<()> {
@@ -367,45 +531,75 @@ impl<Mode: self::Mode> Keyboard<Mode> {
     }
 }
 
-impl Keyboard<Bootloader> {
-    pub fn flash<T: SizedBuffer>(
+impl<T: Transport> Keyboard<Bootloader, T> {
+    pub fn flash<B: SizedBuffer>(
+        &mut self,
+        firmawre: Firmware<B>,
+        options: FlashingOptions,
+    ) -> Result<()> {
+        self.flash_with(firmawre, options, &mut ())
+    }
+
+    /// Same as [`Keyboard::flash`] but reports every step to `observer`
+    pub fn flash_with<B: SizedBuffer>(
+        &mut self,
+        firmawre: Firmware<B>,
+        options: FlashingOptions,
+        observer: &mut impl Observer,
+    ) -> Result<()> {
+        self.write_with(firmawre, options, observer)?;
+        self.reboot()?;
+        observer.notify(&Event::Reboot);
+
+        Ok(())
+    }
+
+    /// Write the firmware without rebooting, so more images can be written in the same session
+    pub fn write_with<B: SizedBuffer>(
         &mut self,
-        mut firmawre: Firmware<T>,
+        mut firmawre: Firmware<B>,
         options: FlashingOptions,
+        observer: &mut impl Observer,
     ) -> Result<()> {
         self.init()?;
+        observer.notify(&Event::Init);
+        // The announced blocks have to be exactly the blocks that are sent
+        let mut image = firmawre.aligned(options.fill)?;
+        if options.wipe_eeprom {
+            // Writing erased pages up to the end of the EEPROM makes QMK start from defaults
+            let end = (options.layout()?.eeprom.end - options.offset()) as usize;
+            image.resize(image.len().max(end), 0xFF);
+        }
+        let chunks = image.len() / BLOCK_SIZE;
+        let blocks = chunks as u32;
         self.write(
             [
                 CMD_PREPARE.to_le_bytes(),
                 options.offset().to_le_bytes(),
-                (firmawre.len()? as u32 / 64).to_le_bytes(),
+                blocks.to_le_bytes(),
             ]
             .into_iter()
             .flatten()
             .collect::<Vec<u8>>(),
             Some(EXPECTED_STATUS.to_le_bytes()),
         )?;
+        observer.notify(&Event::Prepare {
+            offset: options.offset(),
+            blocks,
+        });
 
-        // let firmware = BufReader::new(firmawre.into_inner());
-        // let mut buf = [0u8; 64];
-        // for bytes in &firmware.bytes().chunks(64) {
-        //     let bytes = bytes.collect::<Result<Vec<u8>, std::io::Error>>()?;
-        //     let mut buf = [0u8; 64];
-        //     buf[..bytes.len()].copy_from_slice(&bytes);
-        //     self.set_feature(buf)?;
-        // }
-        // Just read the whole file into memory and then do chunk it
-        let size = (firmawre.len()? as f64 / 64f64).ceil() as usize * 64;
-        let mut buffer = Vec::with_capacity(size);
-        firmawre.inner.read_to_end(&mut buffer)?;
-        for bytes in buffer.chunks(64) {
-            let mut buf = [0u8; 64];
-            buf[..bytes.len()].copy_from_slice(bytes);
-            self.set_feature(buf)?;
+        let start = Instant::now();
+        for (i, bytes) in image.chunks_exact(BLOCK_SIZE).enumerate() {
+            self.set_feature(bytes)?;
+            observer.notify(&Event::Chunk(Progress {
+                chunk: i + 1,
+                chunks,
+                bytes_written: (i + 1) * BLOCK_SIZE,
+                total_bytes: image.len(),
+                elapsed: start.elapsed(),
+            }));
         }
 
-        self.reboot()?;
-
         Ok(())
     }
     /// Reboot the keyboard from bootloder mode
@@ -432,8 +626,8 @@ impl Keyboard<Bootloader> {
 //     time.sleep(5)
 //     complete_cb()
 
-impl Keyboard<Normal> {
-    pub fn reboot(&mut self, bootloader: crate::cli::Bootloader) -> Result<()> {
+impl<T: Transport> Keyboard<Normal, T> {
+    pub fn reboot(&mut self, bootloader: Sequence) -> Result<()> {
         self.set_feature(bootloader.commands())?;
         Ok(())
     }
@@ -0,0 +1,256 @@
+use crate::constants::{CMD_INIT, CMD_PREPARE, CMD_REBOOT, EXPECTED_STATUS, RESPONSE_LEN};
+use crate::flash::Sonix;
+use crate::traits::transport::Transport;
+use crate::Result;
+
+/// Status returned for a `CMD_PREPARE` the bootloader refuses
+pub const FAILED_STATUS: u32 = 0x0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for `CMD_INIT`
+    Idle,
+    /// Initialized and waiting for a command
+    Ready,
+    /// Every report is treated as 64 bytes of data until `remaining` reaches 0
+    Writing { address: usize, remaining: u32 },
+}
+
+/// A software model of the SN32F2xx bootloader
+///
+/// It speaks the same feature report protocol as the real bootloader so it can be used as the
+/// [`Transport`] of a [`Keyboard`](crate::devices::Keyboard). Flash starts out erased (`0xFF`) and
+/// data reports are written at the offset given in `CMD_PREPARE`.
+#[derive(Debug, Clone)]
+pub struct Emulator {
+    chip: Sonix,
+    flash: Vec<u8>,
+    state: State,
+    response: [u8; RESPONSE_LEN],
+    bootloader_overwritten: bool,
+    rebooted: bool,
+}
+
+impl Emulator {
+    pub fn new(chip: Sonix) -> Self {
+        Self {
+            chip,
+            flash: vec![0xFF; chip.flash_size()],
+            state: State::Idle,
+            response: [0; RESPONSE_LEN],
+            bootloader_overwritten: false,
+            rebooted: false,
+        }
+    }
+
+    pub fn chip(&self) -> Sonix {
+        self.chip
+    }
+
+    /// The whole simulated flash
+    pub fn flash(&self) -> &[u8] {
+        &self.flash
+    }
+
+    /// Whether a data report landed in the protected bootloader region of the chip
+    pub fn bootloader_overwritten(&self) -> bool {
+        self.bootloader_overwritten
+    }
+
+    /// Whether `CMD_REBOOT` was received
+    pub fn rebooted(&self) -> bool {
+        self.rebooted
+    }
+
+    /// Power cycle the bootloader, the flash contents are kept
+    pub fn reset(&mut self) {
+        self.state = State::Idle;
+        self.response = [0; RESPONSE_LEN];
+        self.rebooted = false;
+    }
+
+    /// Handle a single report without the report id
+    pub fn handle(&mut self, report: &[u8]) {
+        let mut data = [0u8; RESPONSE_LEN];
+        let len = report.len().min(RESPONSE_LEN);
+        data[..len].copy_from_slice(&report[..len]);
+
+        if let State::Writing { address, remaining } = self.state {
+            self.program(address, &data);
+            self.state = match remaining - 1 {
+                0 => State::Ready,
+                remaining => State::Writing {
+                    address: address + RESPONSE_LEN,
+                    remaining,
+                },
+            };
+            return;
+        }
+
+        let word = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        match word(0) {
+            CMD_INIT => {
+                self.state = State::Ready;
+                self.respond(CMD_INIT, self.chip.pid() as u32);
+            }
+            CMD_PREPARE => {
+                let (offset, blocks) = (word(4), word(8));
+                if self.state == State::Idle || !self.fits(offset, blocks) {
+                    self.respond(CMD_PREPARE, FAILED_STATUS);
+                    return;
+                }
+                self.respond(CMD_PREPARE, EXPECTED_STATUS);
+                if blocks > 0 {
+                    self.state = State::Writing {
+                        address: offset as usize,
+                        remaining: blocks,
+                    };
+                }
+            }
+            CMD_REBOOT => {
+                self.state = State::Idle;
+                self.rebooted = true;
+            }
+            command => self.respond(command, 0),
+        }
+    }
+
+    fn fits(&self, offset: u32, blocks: u32) -> bool {
+        (offset as usize)
+            .checked_add(blocks as usize * RESPONSE_LEN)
+            .is_some_and(|end| end <= self.flash.len())
+    }
+
+    fn program(&mut self, address: usize, data: &[u8; RESPONSE_LEN]) {
+        let protected = self.chip.protected();
+        if (address as u32) < protected.end && (address + RESPONSE_LEN) as u32 > protected.start {
+            self.bootloader_overwritten = true;
+        }
+        self.flash[address..address + RESPONSE_LEN].copy_from_slice(data);
+    }
+
+    fn respond(&mut self, command: u32, status: u32) {
+        self.response = [0; RESPONSE_LEN];
+        self.response[..4].copy_from_slice(&command.to_le_bytes());
+        self.response[4..8].copy_from_slice(&status.to_le_bytes());
+    }
+}
+
+impl Transport for Emulator {
+    fn send_feature_report(&mut self, data: &[u8]) -> Result<()> {
+        // data[0] is the report id
+        self.handle(data.get(1..).unwrap_or_default());
+        Ok(())
+    }
+
+    fn get_feature_report(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = buf.len().min(RESPONSE_LEN + 1);
+        if len > 0 {
+            buf[0] = 0;
+            buf[1..len].copy_from_slice(&self.response[..len - 1]);
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::devices::{Bootloader, Keyboard};
+    use crate::error::ErrorKind;
+    use crate::firmware::{Firmware, UnsafeFirmware, VECTORS};
+    use crate::flash::FlashingOptions;
+
+    /// An image with a vector table that is valid at `offset` and a counting pattern after it
+    fn image(len: usize, offset: u32) -> Vec<u8> {
+        let mut image = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+        image[..VECTORS * 4].fill(0);
+        image[..4].copy_from_slice(&0x2000_0700u32.to_le_bytes());
+        for vector in image[4..16].chunks_exact_mut(4) {
+            vector.copy_from_slice(&(offset + 0x101).to_le_bytes());
+        }
+        image
+    }
+
+    fn flash(emulator: &mut Emulator, image: &[u8], options: FlashingOptions) -> Result<()> {
+        let firmware =
+            Firmware::try_from((UnsafeFirmware::from(Cursor::new(image.to_vec())), &options))?;
+        Keyboard::<Bootloader, _>::new(emulator).flash(firmware, options)
+    }
+
+    #[test]
+    fn flashes_at_the_offset() {
+        for chip in Sonix::all() {
+            let offset = chip.offset() as usize;
+            let image = image(1024, chip.offset());
+            let mut emulator = Emulator::new(chip);
+            flash(&mut emulator, &image, chip.into()).unwrap();
+
+            assert!(emulator.rebooted());
+            assert!(!emulator.bootloader_overwritten());
+            assert!(emulator.flash()[..offset].iter().all(|b| *b == 0xFF));
+            assert_eq!(&emulator.flash()[offset..offset + image.len()], &image[..]);
+            assert!(emulator.flash()[offset + image.len()..]
+                .iter()
+                .all(|b| *b == 0xFF));
+        }
+    }
+
+    #[test]
+    fn pads_the_last_block() {
+        let chip = Sonix::SN32F260;
+        let offset = chip.offset() as usize;
+        let mut emulator = Emulator::new(chip);
+        // Leave something other than 0xFF where the padding goes
+        flash(&mut emulator, &image(1024, chip.offset()), chip.into()).unwrap();
+        emulator.reset();
+
+        let image = image(1000, chip.offset());
+        flash(&mut emulator, &image, chip.into()).unwrap();
+        assert_eq!(&emulator.flash()[offset..offset + 1000], &image[..]);
+        assert!(emulator.flash()[offset + 1000..offset + 1024]
+            .iter()
+            .all(|b| *b == 0xFF));
+    }
+
+    #[test]
+    fn refuses_the_protected_region() {
+        let chip = Sonix::SN32F260;
+        let mut options = FlashingOptions::from(chip);
+        options.with_offset(Some(0));
+        let error = options.check_protected(1024).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ErrorKind::ProtectedRegion {
+                start: 0,
+                end: 1024,
+                protected_start: 0,
+                protected_end: 0x200,
+            }
+        ));
+
+        // Without the check the bootloader would be gone
+        let mut emulator = Emulator::new(chip);
+        flash(&mut emulator, &image(1024, 0), options).unwrap();
+        assert!(emulator.bootloader_overwritten());
+    }
+
+    #[test]
+    fn refuses_writes_past_the_end_of_flash() {
+        let chip = Sonix::SN32F260;
+        let mut emulator = Emulator::new(chip);
+        let mut options = FlashingOptions::from(chip);
+        options.with_offset(Some(chip.flash_size() as u32 - 0x100));
+        let firmware = Firmware {
+            inner: Cursor::new(image(0x200, 0)),
+            base: None,
+        };
+        let error = Keyboard::<Bootloader, _>::new(&mut emulator)
+            .flash(firmware, options)
+            .unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::FailedToWrite(_)));
+        assert!(emulator.flash().iter().all(|b| *b == 0xFF));
+    }
+}
@@ -43,8 +43,69 @@ pub enum ErrorKind {
     InvalidIdentifier(String),
     #[error("Device was not specified")]
     UnspecifiedDevice,
-    #[error("Invalid Firmware")]
-    InvalidFirmware,
+    #[error("Multiple devices match {0}, use vid:pid#N, port=, serial= or path= to pick one")]
+    AmbiguousDevice(String),
+    #[error("Invalid Firmware for {chip:?}: {error}")]
+    InvalidFirmware {
+        chip: crate::flash::Sonix,
+        error: crate::firmware::FirmwareError,
+    },
+    #[error("The firmware check failed with {errors} errors and {warnings} warnings")]
+    CheckFailed { errors: usize, warnings: usize },
+    #[error("Invalid Intel HEX on line {line}: {error}")]
+    InvalidHex {
+        line: usize,
+        error: crate::firmware::ihex::HexError,
+    },
+    #[error("Invalid ELF: {0}")]
+    InvalidElf(crate::firmware::elf::ElfError),
+    #[error("Invalid UF2 in block {block}: {error}")]
+    InvalidUf2 {
+        block: usize,
+        error: crate::firmware::uf2::Uf2Error,
+    },
+    #[error("Invalid DFU suffix: {0}")]
+    InvalidDfu(crate::firmware::dfu::DfuError),
+    #[error("The firmware is for {vendor_id:04x}:{product_id:04x} but the keyboard is {device}")]
+    DfuDeviceMismatch {
+        vendor_id: u16,
+        product_id: u16,
+        device: String,
+    },
+    #[error("The firmware starts at {firmware:#x} but the offset is {offset:#x}")]
+    OffsetMismatch { firmware: u32, offset: u32 },
+    #[error("The vector table says the firmware was linked for {inferred:#x} but the offset is {offset:#x}")]
+    InferredOffsetMismatch { inferred: u32, offset: u32 },
+    #[error("Flashing {start:#x}..{end:#x} overwrites the bootloader at {protected_start:#x}..{protected_end:#x}")]
+    ProtectedRegion {
+        start: u32,
+        end: u32,
+        protected_start: u32,
+        protected_end: u32,
+    },
+    #[error("Flashing {start:#x}..{end:#x} overwrites the emulated EEPROM at {eeprom_start:#x}..{eeprom_end:#x}")]
+    EepromOverlap {
+        start: u32,
+        end: u32,
+        eeprom_start: u32,
+        eeprom_end: u32,
+    },
+    #[error("An EEPROM of {size} bytes does not fit into the flash of the {chip:?}")]
+    InvalidEepromSize {
+        size: u32,
+        chip: crate::flash::Sonix,
+    },
+    #[error("Invalid device database {path}: {error}")]
+    InvalidDatabase {
+        path: String,
+        error: Box<crate::database::DatabaseError>,
+    },
+    #[error("The operation was not confirmed")]
+    NotConfirmed,
+    #[error("{0} does not look like a jumploader")]
+    NotAJumploader(String),
+    #[error("The firmware is a dump of the bootloader and can't be flashed as an application")]
+    BootloaderDump,
     #[error("Invalid Report Length {0}")]
     InvalidReportLength(usize),
     #[error("Invalid Response")]
@@ -55,6 +116,18 @@ pub enum ErrorKind {
     FailedToWrite(WriteFailure),
     #[error("Invalid Device")]
     InvalidDevice,
+    #[error("The device did not come back after rebooting")]
+    DeviceDidNotComeBack,
+    #[error("The device did not enter bootloader mode")]
+    FailedToEnterBootloader,
+    #[error("The device is still in bootloader mode after rebooting")]
+    StuckInBootloader,
+    #[error("Flashing crashed: {0}")]
+    Panicked(String),
+    #[error("{0} of the devices failed to flash")]
+    BatchFailed(usize),
+    #[error("{0} is not supported on this platform")]
+    Unsupported(&'static str),
 }
 
 #[derive(Debug)]
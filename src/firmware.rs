@@ -1,25 +1,158 @@
-use std::io::SeekFrom;
+pub mod dfu;
+pub mod elf;
+pub mod ihex;
+pub mod info;
+pub mod jumploader;
+pub mod kind;
+pub mod lint;
+pub mod offset;
+pub mod uf2;
+pub mod usb;
 
-use crate::constants::MAX_FIRMWARE;
+use std::collections::BTreeMap;
+use std::io::{Cursor, SeekFrom};
+use std::path::Path;
+
+use crate::constants::BLOCK_SIZE;
 use crate::error::ErrorKind;
+use crate::flash::{FlashingOptions, Sonix};
 use crate::traits::buffer::SizedBuffer;
 use crate::Result;
 
+/// Refuse images spanning more than this, a stray address would otherwise make us allocate
+/// gigabytes of padding
+pub const MAX_SPAN: u32 = 16 * 1024 * 1024;
+/// Entries in the vector table, the stack pointer, 15 system exceptions and 32 interrupts
+pub const VECTORS: usize = 48;
+/// Anything shorter can't hold a vector table and a reset handler
+pub const MIN_LEN: usize = 0x100;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum FirmwareError {
+    #[error("the image is only {0} bytes")]
+    TooSmall(usize),
+    #[error("the image is {len} bytes but only {max} bytes fit after the offset")]
+    TooLarge { len: usize, max: usize },
+    #[error("the initial stack pointer {sp:#010x} is outside of ram {start:#010x}..={end:#010x}")]
+    StackPointer { sp: u32, start: u32, end: u32 },
+    #[error("vector {0} is missing")]
+    MissingVector(usize),
+    #[error("vector {index} at {address:#010x} is not a thumb address")]
+    NotThumb { index: usize, address: u32 },
+    #[error("vector {index} at {address:#010x} points outside of the application")]
+    OutsideApplication { index: usize, address: u32 },
+}
+
+/// The file formats firmware can be loaded from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A raw image
+    Bin,
+    IntelHex,
+    Elf,
+    Uf2,
+}
+
+/// A flat image and the flash address of its first byte
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub base: u32,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// Lay out non overlapping chunks at their addresses, gaps are filled with 0xFF like erased
+    /// flash
+    ///
+    /// Returns `None` if there are no chunks or they span more than [`MAX_SPAN`] bytes.
+    pub fn assemble(chunks: &BTreeMap<u32, Vec<u8>>) -> Option<Self> {
+        let (base, _) = chunks.first_key_value()?;
+        let (start, last) = chunks.last_key_value()?;
+        let end = *start as u64 + last.len() as u64;
+        let span = end - *base as u64;
+        if span > MAX_SPAN as u64 {
+            return None;
+        }
+        let mut data = vec![0xFF; span as usize];
+        for (start, chunk) in chunks {
+            let offset = (start - base) as usize;
+            data[offset..offset + chunk.len()].copy_from_slice(chunk);
+        }
+        Some(Self { base: *base, data })
+    }
+}
+
+impl Format {
+    /// Guess the format from the file extension and the contents
+    pub fn detect(path: &Path, data: &[u8]) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("uf2") => Format::Uf2,
+            _ if uf2::looks_like_uf2(data) => Format::Uf2,
+            Some("elf") => Format::Elf,
+            _ if data.starts_with(&elf::MAGIC) => Format::Elf,
+            Some("hex" | "ihex" | "ihx") => Format::IntelHex,
+            _ if ihex::looks_like_hex(data) => Format::IntelHex,
+            _ => Format::Bin,
+        }
+    }
+}
+
 pub struct UnsafeFirmware<T: SizedBuffer> {
     pub inner: T,
+    /// The flash address the image starts at, if the file format records it
+    pub base: Option<u32>,
+    /// The format the image was loaded from
+    pub format: Format,
+    /// The DFU suffix that was stripped off the image
+    pub dfu: Option<dfu::Suffix>,
+}
+
+impl UnsafeFirmware<Cursor<Vec<u8>>> {
+    /// Load a firmware file in any of the supported [`Format`]s as a flat image, UF2 files are
+    /// reduced to the blocks of `family`
+    pub fn open(path: impl AsRef<Path>, family: Option<u32>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)?;
+        let format = Format::detect(path, &data);
+        let image = match format {
+            Format::Bin => {
+                let (len, dfu) = match dfu::strip(&data)? {
+                    Some((image, suffix)) => (image.len(), Some(suffix)),
+                    None => (data.len(), None),
+                };
+                let mut data = data;
+                data.truncate(len);
+                return Ok(Self {
+                    inner: Cursor::new(data),
+                    base: None,
+                    format,
+                    dfu,
+                });
+            }
+            Format::IntelHex => ihex::parse(&String::from_utf8_lossy(&data))?,
+            Format::Elf => elf::Elf::parse(&data)?.image(&data)?,
+            Format::Uf2 => uf2::parse(&data, family)?.image,
+        };
+        Ok(Self {
+            inner: Cursor::new(image.data),
+            base: Some(image.base),
+            format,
+            dfu: None,
+        })
+    }
 }
 
 impl<T: SizedBuffer> UnsafeFirmware<T> {
-    fn header(&mut self) -> Result<[u32; 4]> {
-        let mut buf = [0; 16];
+    /// The start of the vector table, the initial stack pointer followed by the handlers
+    fn vectors(&mut self) -> Result<[u32; VECTORS]> {
+        let mut buf = [0; VECTORS * 4];
         self.inner.read_exact(&mut buf)?;
         self.inner.seek(SeekFrom::Current(-(buf.len() as i64)))?;
-        Ok([
-            u32::from_le_bytes(buf[0..4].try_into()?),
-            u32::from_le_bytes(buf[4..8].try_into()?),
-            u32::from_le_bytes(buf[8..12].try_into()?),
-            u32::from_le_bytes(buf[12..16].try_into()?),
-        ])
+        Ok(vector_table(&buf))
     }
 
     pub fn len(&self) -> Result<usize> {
@@ -30,26 +163,104 @@ impl<T: SizedBuffer> UnsafeFirmware<T> {
         Ok(self.len()? == 0)
     }
 
+    /// Check that the image is valid for at least one chip
+    ///
+    /// The image is placed at its base address if the file format records it, otherwise at the
+    /// application offset of each chip.
     pub fn check(&mut self) -> Result<()> {
-        if self.len()? < 0x100 && self.len()? > MAX_FIRMWARE {
-            return Err(ErrorKind::InvalidFirmware.into());
+        let mut first = None;
+        for chip in Sonix::all() {
+            match self.check_for(chip, self.base.unwrap_or(chip.offset())) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    first.get_or_insert(e);
+                }
+            }
         }
+        Err(first.expect("There is always a chip"))
+    }
 
-        let [sp, vecs @ ..] = self.header()?;
-        if !(0x20000000..=0x20000800).contains(&sp)
-            || vecs[0] & 1 != 1
-            || vecs[1] & 1 != 1
-            || vecs[2] & 1 != 1
-        {
-            return Err(ErrorKind::InvalidFirmware.into());
+    /// Check that the image fits into the application region of `chip` starting at `offset`,
+    /// see [`problems`]
+    pub fn check_for(&mut self, chip: Sonix, offset: u32) -> Result<()> {
+        let len = self.len()?;
+        let vectors = if len < MIN_LEN {
+            [0; VECTORS]
+        } else {
+            self.vectors()?
+        };
+        match problems(len, &vectors, chip, offset).into_iter().next() {
+            Some(error) => Err(ErrorKind::InvalidFirmware { chip, error }.into()),
+            None => Ok(()),
         }
-        Ok(())
     }
 }
 
+/// The vector table at the start of `data`, missing entries are 0
+pub fn vector_table(data: &[u8]) -> [u32; VECTORS] {
+    let mut vectors = [0; VECTORS];
+    for (vector, bytes) in vectors.iter_mut().zip(data.chunks_exact(4)) {
+        *vector = u32::from_le_bytes(bytes.try_into().expect("Chunks of 4"));
+    }
+    vectors
+}
+
+/// Everything that stops an image of `len` bytes with this vector table from running on `chip`
+/// when flashed at `offset`
+///
+/// The image has to fit between the offset and the end of flash, the initial stack pointer has to
+/// be inside the chip's ram and every vector has to be a thumb address inside the application.
+pub fn problems(
+    len: usize,
+    vectors: &[u32; VECTORS],
+    chip: Sonix,
+    offset: u32,
+) -> Vec<FirmwareError> {
+    let end = chip.flash_size() as u32;
+    if len < MIN_LEN {
+        return vec![FirmwareError::TooSmall(len)];
+    }
+    let mut problems = Vec::new();
+    let max = end.saturating_sub(offset) as usize;
+    if len > max {
+        problems.push(FirmwareError::TooLarge { len, max });
+    }
+
+    let [sp, handlers @ ..] = vectors;
+    let ram = chip.ram();
+    // The stack grows down so the initial stack pointer may be the end of ram
+    if *sp <= ram.start || *sp > ram.end {
+        problems.push(FirmwareError::StackPointer {
+            sp: *sp,
+            start: ram.start,
+            end: ram.end,
+        });
+    }
+    let app = offset..end;
+    for (i, &address) in handlers.iter().enumerate() {
+        let index = i + 1;
+        if address == 0 {
+            // Reset, NMI and HardFault can't be left out, the rest may be reserved or unused
+            if index <= 3 {
+                problems.push(FirmwareError::MissingVector(index));
+            }
+        } else if address & 1 != 1 {
+            problems.push(FirmwareError::NotThumb { index, address });
+        } else if !app.contains(&(address & !1)) {
+            problems.push(FirmwareError::OutsideApplication { index, address });
+        }
+    }
+    problems
+}
+
 impl<T: SizedBuffer> From<T> for UnsafeFirmware<T> {
     fn from(inner: T) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            base: None,
+            format: Format::Bin,
+            dfu: None,
+        }
     }
 }
 
@@ -59,7 +270,26 @@ impl<T: SizedBuffer> TryFrom<UnsafeFirmware<T>> for Firmware<T> {
     fn try_from(value: UnsafeFirmware<T>) -> Result<Self, Self::Error> {
         let mut value = value;
         value.check()?;
-        Ok(Self { inner: value.inner })
+        Ok(Self {
+            inner: value.inner,
+            base: value.base,
+        })
+    }
+}
+
+impl<T: SizedBuffer> TryFrom<(UnsafeFirmware<T>, &FlashingOptions)> for Firmware<T> {
+    type Error = crate::error::Error;
+
+    /// Check the firmware against the chip and offset it is going to be flashed to
+    fn try_from(
+        (value, options): (UnsafeFirmware<T>, &FlashingOptions),
+    ) -> Result<Self, Self::Error> {
+        let mut value = value;
+        value.check_for(options.device_type, options.offset())?;
+        Ok(Self {
+            inner: value.inner,
+            base: value.base,
+        })
     }
 }
 
@@ -88,6 +318,8 @@ impl<T: SizedBuffer> TryFrom<UnsafeFirmware<T>> for Firmware<T> {
 
 pub struct Firmware<T: SizedBuffer> {
     pub inner: T,
+    /// The flash address the image starts at, if the file format records it
+    pub base: Option<u32>,
 }
 
 impl<T: SizedBuffer> Firmware<T> {
@@ -99,7 +331,60 @@ impl<T: SizedBuffer> Firmware<T> {
         Ok(self.len()? == 0)
     }
 
+    /// The image padded with `fill` to whole blocks, exactly what gets written to flash
+    pub fn aligned(&mut self, fill: u8) -> Result<Vec<u8>> {
+        let mut image = Vec::with_capacity(self.len()?.div_ceil(BLOCK_SIZE) * BLOCK_SIZE);
+        self.inner.seek(SeekFrom::Start(0))?;
+        self.inner.read_to_end(&mut image)?;
+        image.resize(image.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, fill);
+        Ok(image)
+    }
+
     pub fn into_inner(self) -> T {
         self.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aligned(len: usize, fill: u8) -> Vec<u8> {
+        let mut firmware = Firmware {
+            inner: Cursor::new(vec![0x5A; len]),
+            base: None,
+        };
+        firmware.aligned(fill).unwrap()
+    }
+
+    #[test]
+    fn aligns_to_whole_blocks() {
+        for (len, blocks) in [(0, 0), (1, 1), (63, 1), (64, 1), (65, 2)] {
+            let image = aligned(len, 0xA5);
+            assert_eq!(image.len(), blocks * BLOCK_SIZE, "{} bytes", len);
+            assert!(image[..len].iter().all(|b| *b == 0x5A), "{} bytes", len);
+            assert!(image[len..].iter().all(|b| *b == 0xA5), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn pads_with_the_fill_byte() {
+        assert_eq!(aligned(1, 0xFF)[1..], [0xFF; BLOCK_SIZE - 1]);
+        assert_eq!(aligned(1, 0x00)[1..], [0x00; BLOCK_SIZE - 1]);
+    }
+
+    #[test]
+    fn pads_to_whole_pages() {
+        for chip in Sonix::all() {
+            for len in [1, 63, 64, 65, 1000] {
+                let image = aligned(len, 0xFF);
+                assert!(
+                    image.len().is_multiple_of(chip.page_size()),
+                    "{:?}: {} bytes",
+                    chip,
+                    len
+                );
+            }
+        }
+    }
+}
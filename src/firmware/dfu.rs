@@ -0,0 +1,91 @@
+use crate::error::ErrorKind;
+use crate::Result;
+
+/// The suffix is always the last 16 bytes of the file
+pub const SUFFIX_LEN: usize = 16;
+const SIGNATURE: &[u8; 3] = b"UFD";
+/// Matches any vendor or product id
+pub const WILDCARD: u16 = 0xFFFF;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum DfuError {
+    #[error("suffix length is {0} instead of 16")]
+    InvalidLength(u8),
+    #[error("bad CRC, expected {expected:#010x} but the suffix says {found:#010x}")]
+    BadCrc { expected: u32, found: u32 },
+    #[error("there is no image in front of the suffix")]
+    Empty,
+}
+
+fn error(error: DfuError) -> crate::error::Error {
+    ErrorKind::InvalidDfu(error).into()
+}
+
+/// The DFU 1.1 file suffix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suffix {
+    pub release: u16,
+    pub product_id: u16,
+    pub vendor_id: u16,
+    pub dfu_version: u16,
+    pub crc: u32,
+}
+
+impl Suffix {
+    /// Whether the suffix allows flashing a device with this vid:pid, `0xFFFF` matches anything
+    pub fn matches(&self, vendor_id: u16, product_id: u16) -> bool {
+        (self.vendor_id == WILDCARD || self.vendor_id == vendor_id)
+            && (self.product_id == WILDCARD || self.product_id == product_id)
+    }
+
+    /// Fail unless the suffix matches one of the vid:pids the keyboard was seen with
+    pub fn check(&self, identities: &[(u16, u16)]) -> Result<()> {
+        if identities.iter().any(|(vid, pid)| self.matches(*vid, *pid)) {
+            return Ok(());
+        }
+        Err(ErrorKind::DfuDeviceMismatch {
+            vendor_id: self.vendor_id,
+            product_id: self.product_id,
+            device: identities
+                .iter()
+                .map(|(vid, pid)| format!("{:04x}:{:04x}", vid, pid))
+                .collect::<Vec<_>>()
+                .join(" / "),
+        }
+        .into())
+    }
+}
+
+/// Split off and validate the DFU suffix, `None` if the data doesn't end in one
+pub fn strip(data: &[u8]) -> Result<Option<(&[u8], Suffix)>> {
+    let Some(split) = data.len().checked_sub(SUFFIX_LEN) else {
+        return Ok(None);
+    };
+    let (image, suffix) = data.split_at(split);
+    let u16_at = |at: usize| u16::from_le_bytes([suffix[at], suffix[at + 1]]);
+    if &suffix[8..11] != SIGNATURE {
+        return Ok(None);
+    }
+    if suffix[11] as usize != SUFFIX_LEN {
+        return Err(error(DfuError::InvalidLength(suffix[11])));
+    }
+    let found = u32::from_le_bytes(suffix[12..16].try_into()?);
+    // DFU leaves out the final inversion of the usual CRC-32
+    let expected = !crc32fast::hash(&data[..data.len() - 4]);
+    if expected != found {
+        return Err(error(DfuError::BadCrc { expected, found }));
+    }
+    if image.is_empty() {
+        return Err(error(DfuError::Empty));
+    }
+    Ok(Some((
+        image,
+        Suffix {
+            release: u16_at(0),
+            product_id: u16_at(2),
+            vendor_id: u16_at(4),
+            dfu_version: u16_at(6),
+            crc: found,
+        },
+    )))
+}
@@ -0,0 +1,400 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+use super::Image;
+use crate::error::ErrorKind;
+use crate::Result;
+
+pub const MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_ARM: u16 = 40;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHF_ALLOC: u32 = 0x2;
+/// Anything from here on is ram, never flash
+const RAM_START: u32 = 0x2000_0000;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ElfError {
+    #[error("not an ELF file")]
+    NotElf,
+    #[error("not a 32-bit ELF file")]
+    Not32Bit,
+    #[error("not a little endian ELF file")]
+    NotLittleEndian,
+    #[error("built for machine {0} instead of ARM")]
+    NotArm(u16),
+    #[error("the file is truncated")]
+    Truncated,
+    #[error("no loadable segments in flash")]
+    NoLoadableSegments,
+    #[error("segments overlap at {0:#x}")]
+    Overlap(u32),
+    #[error("segments span more than {} bytes", super::MAX_SPAN)]
+    TooLarge,
+}
+
+fn error(error: ElfError) -> crate::error::Error {
+    ErrorKind::InvalidElf(error).into()
+}
+
+/// A `PT_LOAD` program header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// The load address, where the bytes end up in flash
+    pub paddr: u32,
+    /// The address the segment runs at, differs from `paddr` for `.data`
+    pub vaddr: u32,
+    pub offset: u32,
+    pub filesz: u32,
+    pub memsz: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    pub addr: u32,
+    pub size: u32,
+    /// Whether the section takes up memory on the target
+    pub alloc: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u32,
+    pub size: u32,
+}
+
+/// The parts of a 32-bit little endian ARM ELF file needed to build a flash image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+}
+
+struct Reader<'d>(&'d [u8]);
+
+impl Reader<'_> {
+    fn bytes(&self, at: u32, len: u32) -> Result<&[u8]> {
+        let start = at as usize;
+        let end = start
+            .checked_add(len as usize)
+            .ok_or_else(|| error(ElfError::Truncated))?;
+        self.0
+            .get(start..end)
+            .ok_or_else(|| error(ElfError::Truncated))
+    }
+
+    fn u16(&self, at: u32) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(at, 2)?.try_into()?))
+    }
+
+    fn u32(&self, at: u32) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(at, 4)?.try_into()?))
+    }
+
+    /// A nul terminated string from a string table
+    fn str(&self, table: u32, at: u32) -> Result<String> {
+        let start = table as usize + at as usize;
+        let rest = self
+            .0
+            .get(start..)
+            .ok_or_else(|| error(ElfError::Truncated))?;
+        let end = rest
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(|| error(ElfError::Truncated))?;
+        Ok(String::from_utf8_lossy(&rest[..end]).into_owned())
+    }
+}
+
+impl Elf {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let r = Reader(data);
+        if !data.starts_with(&MAGIC) {
+            return Err(error(ElfError::NotElf));
+        }
+        let ident = r.bytes(0, 16)?;
+        if ident[4] != ELFCLASS32 {
+            return Err(error(ElfError::Not32Bit));
+        }
+        if ident[5] != ELFDATA2LSB {
+            return Err(error(ElfError::NotLittleEndian));
+        }
+        let machine = r.u16(18)?;
+        if machine != EM_ARM {
+            return Err(error(ElfError::NotArm(machine)));
+        }
+        let entry = r.u32(24)?;
+        let (phoff, shoff) = (r.u32(28)?, r.u32(32)?);
+        let (phentsize, phnum) = (r.u16(42)? as u32, r.u16(44)? as u32);
+        let (shentsize, shnum, shstrndx) = (r.u16(46)? as u32, r.u16(48)? as u32, r.u16(50)?);
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = phoff.saturating_add(i.saturating_mul(phentsize));
+            if r.u32(ph)? != PT_LOAD {
+                continue;
+            }
+            segments.push(Segment {
+                offset: r.u32(ph + 4)?,
+                vaddr: r.u32(ph + 8)?,
+                paddr: r.u32(ph + 12)?,
+                filesz: r.u32(ph + 16)?,
+                memsz: r.u32(ph + 20)?,
+            });
+        }
+
+        // (name, type, flags, addr, offset, size, link)
+        let headers = (0..shnum)
+            .map(|i| {
+                let sh = shoff.saturating_add(i.saturating_mul(shentsize));
+                Ok((
+                    r.u32(sh)?,
+                    r.u32(sh + 4)?,
+                    r.u32(sh + 8)?,
+                    r.u32(sh + 12)?,
+                    r.u32(sh + 16)?,
+                    r.u32(sh + 20)?,
+                    r.u32(sh + 24)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let names = headers.get(shstrndx as usize).map(|h| h.4);
+        let sections = headers
+            .iter()
+            .skip(1)
+            .map(|&(name, _, flags, addr, _, size, _)| {
+                Ok(Section {
+                    name: match names {
+                        Some(table) => r.str(table, name)?,
+                        None => String::new(),
+                    },
+                    addr,
+                    size,
+                    alloc: flags & SHF_ALLOC != 0,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut symbols = Vec::new();
+        for &(_, kind, _, _, offset, size, link) in &headers {
+            if kind != SHT_SYMTAB {
+                continue;
+            }
+            let strings = headers
+                .get(link as usize)
+                .ok_or_else(|| error(ElfError::Truncated))?
+                .4;
+            // The first symbol is always the null symbol
+            for sym in (offset..offset.saturating_add(size)).step_by(16).skip(1) {
+                let name = r.str(strings, r.u32(sym)?)?;
+                if name.is_empty() {
+                    continue;
+                }
+                symbols.push(Symbol {
+                    name,
+                    value: r.u32(sym + 4)?,
+                    size: r.u32(sym + 8)?,
+                });
+            }
+        }
+
+        Ok(Self {
+            entry,
+            segments,
+            sections,
+            symbols,
+        })
+    }
+
+    /// The segments that end up in flash
+    pub fn loadable(&self) -> impl Iterator<Item = &Segment> {
+        self.segments
+            .iter()
+            .filter(|s| s.filesz > 0 && s.paddr < RAM_START)
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    /// Lay out the loadable segments by their load address
+    pub fn image(&self, data: &[u8]) -> Result<Image> {
+        let r = Reader(data);
+        let mut chunks = BTreeMap::<u32, Vec<u8>>::new();
+        for segment in self.loadable() {
+            let bytes = r.bytes(segment.offset, segment.filesz)?;
+            let end = segment.paddr as u64 + segment.filesz as u64;
+            let overlaps = chunks
+                .range(..end.min(u32::MAX as u64) as u32)
+                .next_back()
+                .is_some_and(|(start, chunk)| {
+                    *start as u64 + chunk.len() as u64 > segment.paddr as u64
+                });
+            if overlaps {
+                return Err(error(ElfError::Overlap(segment.paddr)));
+            }
+            chunks.insert(segment.paddr, bytes.to_vec());
+        }
+        if chunks.is_empty() {
+            return Err(error(ElfError::NoLoadableSegments));
+        }
+        Image::assemble(&chunks).ok_or_else(|| error(ElfError::TooLarge))
+    }
+}
+
+impl Display for Elf {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Entry point: {:#010x}", self.entry)?;
+        writeln!(f, "Loadable segments:")?;
+        for s in self.loadable() {
+            writeln!(
+                f,
+                "  load {:#010x} run {:#010x} {} bytes",
+                s.paddr, s.vaddr, s.filesz
+            )?;
+        }
+        writeln!(f, "Sections:")?;
+        for s in self.sections.iter().filter(|s| s.alloc && s.size > 0) {
+            writeln!(f, "  {:<16} {:#010x} {} bytes", s.name, s.addr, s.size)?;
+        }
+        for name in ["_vectors", "Reset_Handler", "main"] {
+            if let Some(symbol) = self.symbol(name) {
+                writeln!(f, "Symbol {:<16} {:#010x}", symbol.name, symbol.value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PT_NOTE: u32 = 4;
+
+    /// A program header: (type, offset, vaddr, paddr, filesz, memsz)
+    type Header = (u32, u32, u32, u32, u32, u32);
+
+    /// A 32-bit little endian ARM ELF file with program headers but no sections, followed by
+    /// `payload`
+    fn file(headers: &[Header], payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 52];
+        data[..4].copy_from_slice(&MAGIC);
+        data[4] = ELFCLASS32;
+        data[5] = ELFDATA2LSB;
+        data[6] = 1;
+        data[16..18].copy_from_slice(&2u16.to_le_bytes());
+        data[18..20].copy_from_slice(&EM_ARM.to_le_bytes());
+        data[24..28].copy_from_slice(&0x201u32.to_le_bytes());
+        data[28..32].copy_from_slice(&52u32.to_le_bytes());
+        data[40..42].copy_from_slice(&52u16.to_le_bytes());
+        data[42..44].copy_from_slice(&32u16.to_le_bytes());
+        data[44..46].copy_from_slice(&(headers.len() as u16).to_le_bytes());
+        for &(kind, offset, vaddr, paddr, filesz, memsz) in headers {
+            for word in [kind, offset, vaddr, paddr, filesz, memsz, 0, 0] {
+                data.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        data.extend_from_slice(payload);
+        data
+    }
+
+    /// Where the payload starts for `headers` program headers
+    fn payload_at(headers: u32) -> u32 {
+        52 + 32 * headers
+    }
+
+    fn elf_error(result: Result<impl Sized>) -> ElfError {
+        match result.map(|_| ()).unwrap_err().kind {
+            ErrorKind::InvalidElf(error) => error,
+            kind => panic!("Not an ELF error: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn loads_segments_at_their_load_address() {
+        let at = payload_at(4);
+        let data = file(
+            &[
+                // .text
+                (PT_LOAD, at, 0x200, 0x200, 8, 8),
+                // .data runs from ram but is loaded right after .text
+                (PT_LOAD, at + 8, 0x2000_0000, 0x208, 4, 4),
+                // .bss takes no space in the file
+                (PT_LOAD, at + 12, 0x2000_0004, 0x2000_0004, 0, 0x100),
+                (PT_NOTE, at, 0, 0, 4, 4),
+            ],
+            &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+        );
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.entry, 0x201);
+        assert_eq!(elf.segments.len(), 3);
+        let loadable: Vec<_> = elf.loadable().map(|s| (s.paddr, s.vaddr)).collect();
+        assert_eq!(loadable, [(0x200, 0x200), (0x208, 0x2000_0000)]);
+
+        let image = elf.image(&data).unwrap();
+        assert_eq!(image.base, 0x200);
+        assert_eq!(image.data, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn bad_headers() {
+        let data = file(&[], &[]);
+        assert_eq!(elf_error(Elf::parse(b"\x7FELX")), ElfError::NotElf);
+
+        let mut wrong = data.clone();
+        wrong[4] = 2;
+        assert_eq!(elf_error(Elf::parse(&wrong)), ElfError::Not32Bit);
+
+        let mut wrong = data.clone();
+        wrong[5] = 2;
+        assert_eq!(elf_error(Elf::parse(&wrong)), ElfError::NotLittleEndian);
+
+        let mut wrong = data.clone();
+        wrong[18..20].copy_from_slice(&62u16.to_le_bytes());
+        assert_eq!(elf_error(Elf::parse(&wrong)), ElfError::NotArm(62));
+
+        assert_eq!(elf_error(Elf::parse(&data[..40])), ElfError::Truncated);
+    }
+
+    #[test]
+    fn bad_segments() {
+        let at = payload_at(1);
+        let data = file(&[(PT_LOAD, at, 0x2000_0000, 0x2000_0000, 4, 4)], &[0; 4]);
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf_error(elf.image(&data)), ElfError::NoLoadableSegments);
+
+        let at = payload_at(2);
+        let data = file(
+            &[
+                (PT_LOAD, at, 0x200, 0x200, 8, 8),
+                (PT_LOAD, at, 0x204, 0x204, 8, 8),
+            ],
+            &[0; 8],
+        );
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf_error(elf.image(&data)), ElfError::Overlap(0x204));
+
+        // The segment claims more bytes than the file has
+        let at = payload_at(1);
+        let data = file(&[(PT_LOAD, at, 0x200, 0x200, 16, 16)], &[0; 8]);
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf_error(elf.image(&data)), ElfError::Truncated);
+
+        let data = file(
+            &[
+                (PT_LOAD, at, 0, 0, 4, 4),
+                (PT_LOAD, at, 0, 0x1000_0000, 4, 4),
+            ],
+            &[0; 4],
+        );
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf_error(elf.image(&data)), ElfError::TooLarge);
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+
+use super::{Image, MAX_SPAN};
+use crate::error::ErrorKind;
+use crate::Result;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum HexError {
+    #[error("record does not start with ':'")]
+    MissingStartCode,
+    #[error("invalid hex digit {0:?}")]
+    InvalidDigit(char),
+    #[error("odd number of hex digits")]
+    OddLength,
+    #[error("record is too short")]
+    TooShort,
+    #[error("byte count says {expected} data bytes but the record has {found}")]
+    LengthMismatch { expected: usize, found: usize },
+    #[error("bad checksum, expected {expected:#04x} but found {found:#04x}")]
+    BadChecksum { expected: u8, found: u8 },
+    #[error("unknown record type {0:#04x}")]
+    UnknownRecordType(u8),
+    #[error("record type {record_type:#04x} needs {expected} data bytes but has {found}")]
+    InvalidRecordLength {
+        record_type: u8,
+        expected: usize,
+        found: usize,
+    },
+    #[error("data overlaps earlier data at {0:#x}")]
+    Overlap(u32),
+    #[error("data after the end of file record")]
+    DataAfterEof,
+    #[error("missing end of file record")]
+    MissingEof,
+    #[error("no data records")]
+    Empty,
+    #[error("image spans more than {MAX_SPAN} bytes")]
+    TooLarge,
+}
+
+fn error(line: usize, error: HexError) -> crate::error::Error {
+    ErrorKind::InvalidHex { line, error }.into()
+}
+
+fn decode(record: &str) -> Result<Vec<u8>, HexError> {
+    if !record.len().is_multiple_of(2) {
+        return Err(HexError::OddLength);
+    }
+    if let Some(c) = record.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(HexError::InvalidDigit(c));
+    }
+    Ok(hex::decode(record).expect("Already checked for invalid digits and odd length"))
+}
+
+/// Whether the data looks like Intel HEX rather than a raw binary
+pub fn looks_like_hex(data: &[u8]) -> bool {
+    let start = data.iter().position(|b| !b.is_ascii_whitespace());
+    start.is_some_and(|start| data[start] == b':')
+        && data
+            .iter()
+            .all(|b| b.is_ascii_hexdigit() || b.is_ascii_whitespace() || *b == b':')
+}
+
+pub fn parse(text: &str) -> Result<Image> {
+    // Start address of every contiguous run of data
+    let mut chunks = BTreeMap::<u32, Vec<u8>>::new();
+    let mut upper = 0u32;
+    let mut eof = false;
+    let mut last_line = 0;
+
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        last_line = line_no;
+        if eof {
+            return Err(error(line_no, HexError::DataAfterEof));
+        }
+        let record = line
+            .strip_prefix(':')
+            .ok_or_else(|| error(line_no, HexError::MissingStartCode))?;
+        let bytes = decode(record).map_err(|e| error(line_no, e))?;
+        let [count, hi, lo, record_type, rest @ ..] = &bytes[..] else {
+            return Err(error(line_no, HexError::TooShort));
+        };
+        let (data, checksum) = match rest {
+            [data @ .., checksum] => (data, *checksum),
+            [] => return Err(error(line_no, HexError::TooShort)),
+        };
+        if data.len() != *count as usize {
+            return Err(error(
+                line_no,
+                HexError::LengthMismatch {
+                    expected: *count as usize,
+                    found: data.len(),
+                },
+            ));
+        }
+        let sum = bytes[..bytes.len() - 1]
+            .iter()
+            .fold(0u8, |acc, b| acc.wrapping_add(*b));
+        let expected = sum.wrapping_neg();
+        if expected != checksum {
+            return Err(error(
+                line_no,
+                HexError::BadChecksum {
+                    expected,
+                    found: checksum,
+                },
+            ));
+        }
+
+        let address_len = |expected: usize| {
+            if data.len() == expected {
+                Ok(())
+            } else {
+                Err(error(
+                    line_no,
+                    HexError::InvalidRecordLength {
+                        record_type: *record_type,
+                        expected,
+                        found: data.len(),
+                    },
+                ))
+            }
+        };
+        match record_type {
+            0x00 => {
+                let address = upper.wrapping_add(u16::from_be_bytes([*hi, *lo]) as u32);
+                insert(&mut chunks, address, data).map_err(|e| error(line_no, e))?;
+            }
+            0x01 => {
+                address_len(0)?;
+                eof = true;
+            }
+            0x02 => {
+                address_len(2)?;
+                upper = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4;
+            }
+            0x04 => {
+                address_len(2)?;
+                upper = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16;
+            }
+            // Start addresses only matter to debuggers
+            0x03 | 0x05 => address_len(4)?,
+            other => return Err(error(line_no, HexError::UnknownRecordType(*other))),
+        }
+    }
+
+    if !eof {
+        return Err(error(last_line + 1, HexError::MissingEof));
+    }
+    if chunks.is_empty() {
+        return Err(error(last_line, HexError::Empty));
+    }
+    Image::assemble(&chunks).ok_or_else(|| error(last_line, HexError::TooLarge))
+}
+
+/// Add data at `address`, merging it with the chunk it continues
+fn insert(chunks: &mut BTreeMap<u32, Vec<u8>>, address: u32, data: &[u8]) -> Result<(), HexError> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let end = address
+        .checked_add(data.len() as u32)
+        .ok_or(HexError::TooLarge)?;
+    if let Some((start, chunk)) = chunks.range(..end).next_back() {
+        let chunk_end = *start + chunk.len() as u32;
+        if chunk_end > address {
+            return Err(HexError::Overlap(address.max(*start)));
+        }
+        if chunk_end == address {
+            let start = *start;
+            chunks
+                .get_mut(&start)
+                .expect("Key was just found")
+                .extend_from_slice(data);
+            return Ok(());
+        }
+    }
+    chunks.insert(address, data.to_vec());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A record with a correct checksum
+    fn record(record_type: u8, address: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend_from_slice(&address.to_be_bytes());
+        bytes.push(record_type);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        bytes.push(sum.wrapping_neg());
+        format!(":{}\n", hex::encode_upper(bytes))
+    }
+
+    const EOF: &str = ":00000001FF\n";
+
+    fn hex_error(text: &str) -> (usize, HexError) {
+        match parse(text).unwrap_err().kind {
+            ErrorKind::InvalidHex { line, error } => (line, error),
+            kind => panic!("Not a HEX error: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn record_types() {
+        let text = [
+            // Extended segment address 0x1000 << 4
+            record(0x02, 0, &[0x10, 0x00]),
+            record(0x00, 0x0200, &[1, 2]),
+            // Extended linear address, replaces the segment
+            record(0x04, 0, &[0x00, 0x01]),
+            record(0x00, 0x0400, &[3, 4]),
+            record(0x05, 0, &[0x00, 0x01, 0x00, 0x01]),
+            EOF.to_string(),
+        ]
+        .concat();
+        let image = parse(&text).unwrap();
+        assert_eq!(image.base, 0x1_0200);
+        assert_eq!(&image.data[..2], &[1, 2]);
+        assert_eq!(&image.data[image.data.len() - 2..], &[3, 4]);
+        assert_eq!(image.data.len(), 0x202);
+    }
+
+    #[test]
+    fn merges_contiguous_records() {
+        let text = [
+            record(0x00, 0x200, &[1, 2]),
+            record(0x00, 0x202, &[3]),
+            EOF.to_string(),
+        ]
+        .concat();
+        let image = parse(&text).unwrap();
+        assert_eq!((image.base, image.data), (0x200, vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut bad = record(0x00, 0x200, &[1, 2]);
+        bad.replace_range(bad.len() - 3.., "00\n");
+        let text = [record(0x00, 0x100, &[0]), bad, EOF.to_string()].concat();
+        let (line, error) = hex_error(&text);
+        assert_eq!(line, 2);
+        assert!(matches!(error, HexError::BadChecksum { found: 0, .. }));
+    }
+
+    #[test]
+    fn missing_eof() {
+        let text = [record(0x00, 0x200, &[1, 2]), "\n".into()].concat();
+        assert_eq!(hex_error(&text), (2, HexError::MissingEof));
+
+        let text = [
+            record(0x00, 0x200, &[1, 2]),
+            EOF.to_string(),
+            record(0x00, 0x202, &[3]),
+        ]
+        .concat();
+        assert_eq!(hex_error(&text), (3, HexError::DataAfterEof));
+    }
+
+    #[test]
+    fn reports_the_offending_line() {
+        // Blank lines still count
+        let text = [
+            record(0x00, 0x200, &[1, 2]),
+            "\n".into(),
+            record(0x04, 0, &[0]),
+            EOF.to_string(),
+        ]
+        .concat();
+        assert_eq!(
+            hex_error(&text),
+            (
+                3,
+                HexError::InvalidRecordLength {
+                    record_type: 0x04,
+                    expected: 2,
+                    found: 1
+                }
+            )
+        );
+
+        let text = [
+            record(0x00, 0x200, &[1, 2]),
+            record(0x00, 0x201, &[3]),
+            EOF.to_string(),
+        ]
+        .concat();
+        assert_eq!(hex_error(&text), (2, HexError::Overlap(0x201)));
+
+        let text = [
+            record(0x00, 0x200, &[1]),
+            "200000\n".into(),
+            EOF.to_string(),
+        ]
+        .concat();
+        assert_eq!(hex_error(&text), (2, HexError::MissingStartCode));
+    }
+}
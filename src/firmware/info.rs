@@ -0,0 +1,262 @@
+use std::fmt::{Display, Formatter};
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use super::kind::{classify, Kind};
+use super::offset::{infer, Inference};
+use super::usb::{string_descriptors, Usb};
+use super::{problems, vector_table, Format, VECTORS};
+use crate::constants::BLOCK_SIZE;
+use crate::flash::Sonix;
+
+/// Shorter runs of printable bytes are mostly coincidence
+const MIN_STRING: usize = 4;
+
+/// The name of a Cortex-M0 vector table entry
+pub fn vector_name(index: usize) -> String {
+    match index {
+        0 => "Initial SP".into(),
+        1 => "Reset".into(),
+        2 => "NMI".into(),
+        3 => "HardFault".into(),
+        11 => "SVCall".into(),
+        14 => "PendSV".into(),
+        15 => "SysTick".into(),
+        4..=15 => "Reserved".into(),
+        irq => format!("IRQ{}", irq - 16),
+    }
+}
+
+/// How an image fits into a chip
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fit {
+    pub chip: Sonix,
+    pub offset: u32,
+    /// Bytes left between the end of the image and the end of flash, negative if it doesn't fit
+    pub free: i64,
+    /// Whether the image passes every check for the chip
+    pub valid: bool,
+}
+
+/// What kind of embedded string was found
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StringKind {
+    /// Looks like `0.22.14` or `v0.22.14-5-gabcdef`
+    Version,
+    /// Looks like a qmk keyboard path like `redragon/k552/rev1`
+    Keyboard,
+    /// A utf-16 usb string descriptor like the manufacturer or product name
+    Usb,
+}
+
+impl Display for StringKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            StringKind::Version => "version",
+            StringKind::Keyboard => "keyboard",
+            StringKind::Usb => "usb",
+        })
+    }
+}
+
+/// Everything `firmware info` knows about an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Info {
+    pub format: Format,
+    pub kind: Kind,
+    pub base: Option<u32>,
+    pub size: usize,
+    /// The size rounded up to whole blocks
+    pub padded_size: usize,
+    /// The number of 64 byte blocks announced to the bootloader
+    pub blocks: usize,
+    pub vectors: [u32; VECTORS],
+    /// The link base the vector table points to
+    pub inferred: Inference,
+    pub fits: Vec<Fit>,
+    pub crc32: u32,
+    pub sha256: [u8; 32],
+    pub strings: Vec<(StringKind, String)>,
+    pub usb: Usb,
+}
+
+impl Info {
+    pub fn new(data: &[u8], format: Format, base: Option<u32>) -> Self {
+        let vectors = vector_table(data);
+        let kind = classify(data);
+        let fits = Sonix::all()
+            .into_iter()
+            .map(|chip| {
+                let offset = base.unwrap_or(kind.offset(chip));
+                Fit {
+                    chip,
+                    offset,
+                    free: chip.flash_size() as i64 - offset as i64 - data.len() as i64,
+                    valid: problems(data.len(), &vectors, chip, offset).is_empty(),
+                }
+            })
+            .collect();
+        let padded_size = data.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        Self {
+            format,
+            kind,
+            base,
+            size: data.len(),
+            padded_size,
+            blocks: padded_size / BLOCK_SIZE,
+            vectors,
+            inferred: infer(data),
+            fits,
+            crc32: crc32fast::hash(data),
+            sha256: Sha256::digest(data).into(),
+            strings: strings(data),
+            usb: Usb::scan(data),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let vectors: Vec<_> = self
+            .vectors
+            .iter()
+            .enumerate()
+            .map(|(i, address)| json!({ "index": i, "name": vector_name(i), "address": address }))
+            .collect();
+        let chips: Vec<_> = self
+            .fits
+            .iter()
+            .map(|fit| {
+                json!({
+                    "chip": format!("{:?}", fit.chip),
+                    "offset": fit.offset,
+                    "free": fit.free,
+                    "valid": fit.valid,
+                })
+            })
+            .collect();
+        let strings: Vec<_> = self
+            .strings
+            .iter()
+            .map(|(kind, s)| json!({ "kind": kind.to_string(), "value": s }))
+            .collect();
+        json!({
+            "format": format!("{:?}", self.format),
+            "kind": format!("{:?}", self.kind),
+            "base": self.base,
+            "size": self.size,
+            "padded_size": self.padded_size,
+            "blocks": self.blocks,
+            "stack_pointer": self.vectors[0],
+            "linked_for": self.inferred.base(),
+            "vectors": vectors,
+            "chips": chips,
+            "usb": {
+                "vid_pid": self.usb.vid_pid().map(|(vid, pid)| format!("{:04x}:{:04x}", vid, pid)),
+                "release": self.usb.device.as_ref().map(|d| d.release),
+                "known_name": self.usb.known_name(),
+                "manufacturer": self.usb.manufacturer,
+                "product": self.usb.product,
+            },
+            "crc32": format!("{:08x}", self.crc32),
+            "sha256": hex::encode(self.sha256),
+            "strings": strings,
+        })
+    }
+}
+
+impl Display for Info {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Format:        {:?}", self.format)?;
+        writeln!(f, "Kind:          {}", self.kind)?;
+        if let Some(base) = self.base {
+            writeln!(f, "Base:          {:#x}", base)?;
+        }
+        writeln!(f, "Size:          {} bytes", self.size)?;
+        writeln!(f, "Padded size:   {} bytes", self.padded_size)?;
+        writeln!(f, "Blocks:        {}", self.blocks)?;
+        writeln!(f, "Stack pointer: {:#010x}", self.vectors[0])?;
+        match &self.inferred {
+            Inference::Base(base) => writeln!(f, "Linked for:    {:#x}", base)?,
+            Inference::Ambiguous(bases) => writeln!(
+                f,
+                "Linked for:    one of {}",
+                bases
+                    .iter()
+                    .map(|b| format!("{:#x}", b))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )?,
+            Inference::Unknown => writeln!(f, "Linked for:    unknown")?,
+        }
+        writeln!(f, "USB:           {}", self.usb)?;
+        writeln!(f, "CRC32:         {:08x}", self.crc32)?;
+        writeln!(f, "SHA-256:       {}", hex::encode(self.sha256))?;
+        writeln!(f, "Chips:")?;
+        for fit in &self.fits {
+            writeln!(
+                f,
+                "  {:<10} at {:#06x} {:>6} bytes free{}",
+                format!("{:?}", fit.chip),
+                fit.offset,
+                fit.free,
+                if fit.valid { "" } else { " (invalid)" }
+            )?;
+        }
+        writeln!(f, "Vector table:")?;
+        for (i, address) in self.vectors.iter().enumerate().skip(1) {
+            // Unused entries are noise
+            if *address != 0 {
+                writeln!(f, "  {:>2} {:<10} {:#010x}", i, vector_name(i), address)?;
+            }
+        }
+        if !self.strings.is_empty() {
+            writeln!(f, "Strings:")?;
+            for (kind, s) in &self.strings {
+                writeln!(f, "  {:<8} {}", kind, s)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_version(s: &str) -> bool {
+    let s = s.strip_prefix('v').unwrap_or(s);
+    let core = s.split(['-', '+', ' ']).next().unwrap_or_default();
+    let parts = core.split('.').collect::<Vec<_>>();
+    parts.len() == 3
+        && parts
+            .iter()
+            .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn is_keyboard(s: &str) -> bool {
+    s.contains('/')
+        && !s.starts_with(['.', '/'])
+        && !s.ends_with(".c")
+        && !s.ends_with(".h")
+        && s.chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "_/-".contains(c))
+}
+
+/// Strings that look like a qmk version, keyboard name or usb string descriptor
+pub fn strings(data: &[u8]) -> Vec<(StringKind, String)> {
+    let mut found = Vec::new();
+    for run in data.split(|b| !(0x20..0x7F).contains(b)) {
+        if run.len() < MIN_STRING {
+            continue;
+        }
+        let s = String::from_utf8_lossy(run).trim().to_owned();
+        if is_version(&s) {
+            found.push((StringKind::Version, s));
+        } else if is_keyboard(&s) {
+            found.push((StringKind::Keyboard, s));
+        }
+    }
+
+    found.extend(
+        string_descriptors(data)
+            .into_iter()
+            .map(|s| (StringKind::Usb, s)),
+    );
+    found
+}
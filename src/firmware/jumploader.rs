@@ -0,0 +1,84 @@
+use std::io::Cursor;
+
+use super::{vector_table, Firmware, UnsafeFirmware};
+use crate::constants::QMK_OFFSET_DEFAULT;
+
+/// Whether the image looks like a jumploader
+///
+/// A jumploader sits at 0x0 and only jumps to the application at [`QMK_OFFSET_DEFAULT`], so it
+/// has to fit in front of it, its reset handler has to be in there too and the application
+/// address has to show up as a literal.
+pub fn is_jumploader(data: &[u8]) -> bool {
+    let used = data.len() - data.iter().rev().take_while(|b| **b == 0xFF).count();
+    let reset = vector_table(data)[1];
+    let jumps_to_application = data
+        .chunks_exact(4)
+        .skip(1)
+        .any(|word| word == QMK_OFFSET_DEFAULT.to_le_bytes());
+    used > 0
+        && used <= QMK_OFFSET_DEFAULT as usize
+        && reset & 1 == 1
+        && reset < QMK_OFFSET_DEFAULT
+        && jumps_to_application
+}
+
+/// Check an image as a jumploader instead of an application, `None` if it isn't one
+///
+/// Jumploaders are smaller than any application and only fill their slot with a short vector
+/// table, so [`is_jumploader`] is the whole check. The erased padding after it is dropped, it
+/// would overwrite the start of the application.
+pub fn checked(firmware: UnsafeFirmware<Cursor<Vec<u8>>>) -> Option<Firmware<Cursor<Vec<u8>>>> {
+    let mut data = firmware.inner.into_inner();
+    if !is_jumploader(&data) {
+        return None;
+    }
+    data.truncate(QMK_OFFSET_DEFAULT as usize);
+    Some(Firmware {
+        inner: Cursor::new(data),
+        base: firmware.base,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A jumploader with a 4 entry vector table and the address of the application after it
+    fn jumploader(len: usize) -> Vec<u8> {
+        let mut data = vec![0xFF; len];
+        data[..4].copy_from_slice(&0x2000_0700u32.to_le_bytes());
+        for vector in data[4..16].chunks_exact_mut(4) {
+            vector.copy_from_slice(&0x21u32.to_le_bytes());
+        }
+        data[0x20..0x24].copy_from_slice(&[0x00, 0xBF, 0x00, 0xBF]);
+        data[0x24..0x28].copy_from_slice(&QMK_OFFSET_DEFAULT.to_le_bytes());
+        data
+    }
+
+    fn check(data: Vec<u8>) -> Option<Vec<u8>> {
+        checked(UnsafeFirmware::from(Cursor::new(data))).map(|f| f.inner.into_inner())
+    }
+
+    #[test]
+    fn small_jumploaders() {
+        let data = jumploader(0x40);
+        assert_eq!(check(data.clone()), Some(data));
+    }
+
+    #[test]
+    fn drops_the_padding() {
+        let data = jumploader(0x400);
+        assert_eq!(check(data.clone()), Some(data[..0x200].to_vec()));
+    }
+
+    #[test]
+    fn refuses_applications() {
+        let mut data = jumploader(0x400);
+        data[0x300] = 0;
+        assert_eq!(check(data), None);
+
+        let mut data = jumploader(0x40);
+        data[0x24..0x28].fill(0xFF);
+        assert_eq!(check(data), None);
+    }
+}
@@ -0,0 +1,169 @@
+use std::fmt::{Display, Formatter};
+
+use super::jumploader::is_jumploader;
+use super::offset::{infer, reset_instruction, CPSID_I};
+use crate::constants::{CMD_INIT, CMD_PREPARE, CMD_REBOOT, EXPECTED_STATUS};
+use crate::error::ErrorKind;
+use crate::flash::Sonix;
+use crate::reboot::Sequence;
+use crate::Result;
+
+/// What an image most likely is
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// A QMK build on ChibiOS
+    Qmk,
+    /// The firmware the keyboard shipped with
+    Stock,
+    /// A jumploader that starts the application at 0x200
+    Jumploader,
+    /// A dump of the Sonix bootloader, it can't run as an application
+    BootloaderDump,
+    Unknown,
+}
+
+impl Kind {
+    /// The offset an image of this kind goes to on `chip` unless told otherwise
+    pub fn offset(self, chip: Sonix) -> u32 {
+        match self {
+            Kind::Stock => chip.stock_offset(),
+            Kind::Jumploader => 0,
+            _ => chip.offset(),
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Kind::Qmk => "QMK",
+            Kind::Stock => "stock firmware",
+            Kind::Jumploader => "jumploader",
+            Kind::BootloaderDump => "bootloader dump",
+            Kind::Unknown => "unknown",
+        })
+    }
+}
+
+fn contains(data: &[u8], needle: &[u8]) -> bool {
+    data.windows(needle.len()).any(|w| w == needle)
+}
+
+fn has_word(data: &[u8], word: u32) -> bool {
+    data.chunks_exact(4).any(|w| w == word.to_le_bytes())
+}
+
+/// Tell QMK, stock firmware, jumploaders and bootloader dumps apart
+///
+/// - bootloader dumps contain the command words and status of the flashing protocol
+/// - QMK contains `QMK` or `ChibiOS` or has the ChibiOS reset handler
+/// - stock firmware contains the words of a reboot into bootloader sequence
+pub fn classify(data: &[u8]) -> Kind {
+    let protocol = [CMD_INIT, CMD_PREPARE, CMD_REBOOT]
+        .into_iter()
+        .filter(|word| has_word(data, *word))
+        .count();
+    if protocol >= 2 && has_word(data, EXPECTED_STATUS) {
+        return Kind::BootloaderDump;
+    }
+    if is_jumploader(data) {
+        return Kind::Jumploader;
+    }
+    let chibios_reset = infer(data)
+        .base()
+        .and_then(|base| reset_instruction(data, base))
+        == Some(CPSID_I);
+    if contains(data, b"QMK") || contains(data, b"ChibiOS") || chibios_reset {
+        return Kind::Qmk;
+    }
+    let reboots = Sequence::ALL.iter().any(|bootloader| {
+        bootloader.commands().chunks_exact(4).all(|word| {
+            has_word(
+                data,
+                u32::from_le_bytes(word.try_into().expect("Chunks of 4")),
+            )
+        })
+    });
+    if reboots {
+        return Kind::Stock;
+    }
+    Kind::Unknown
+}
+
+/// Bootloader dumps end up in the wrong place all the time, they never run as an application
+pub fn refuse_dump(data: &[u8]) -> Result<Kind> {
+    match classify(data) {
+        Kind::BootloaderDump => Err(ErrorKind::BootloaderDump.into()),
+        kind => Ok(kind),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 0x400 byte application linked at 0x200 with `words` in its body
+    fn image(words: &[u32]) -> Vec<u8> {
+        let mut data = vec![0; 0x400];
+        data[..4].copy_from_slice(&0x2000_0700u32.to_le_bytes());
+        for vector in data[4..16].chunks_exact_mut(4) {
+            vector.copy_from_slice(&0x501u32.to_le_bytes());
+        }
+        for (word, at) in words.iter().zip((0x200..).step_by(4)) {
+            data[at..at + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn qmk() {
+        let mut data = image(&[]);
+        data[0x300..0x302].copy_from_slice(&CPSID_I.to_le_bytes());
+        assert_eq!(classify(&data), Kind::Qmk);
+
+        let mut data = image(&[]);
+        data[0x380..0x387].copy_from_slice(b"ChibiOS");
+        assert_eq!(classify(&data), Kind::Qmk);
+    }
+
+    #[test]
+    fn stock() {
+        let mut data = image(&[]);
+        data[0x200..0x208].copy_from_slice(&Sequence::Hfd.commands());
+        assert_eq!(classify(&data), Kind::Stock);
+        assert_eq!(Kind::Stock.offset(Sonix::SN32F260), 0);
+    }
+
+    #[test]
+    fn jumploader() {
+        let mut data = vec![0xFF; 0x200];
+        data[..4].copy_from_slice(&0x2000_0700u32.to_le_bytes());
+        for vector in data[4..16].chunks_exact_mut(4) {
+            vector.copy_from_slice(&0x21u32.to_le_bytes());
+        }
+        data[0x24..0x28].copy_from_slice(&0x200u32.to_le_bytes());
+        assert_eq!(classify(&data), Kind::Jumploader);
+        assert_eq!(Kind::Jumploader.offset(Sonix::SN32F260), 0);
+    }
+
+    #[test]
+    fn bootloader_dump() {
+        let data = image(&[CMD_INIT, CMD_PREPARE, EXPECTED_STATUS]);
+        assert_eq!(classify(&data), Kind::BootloaderDump);
+        assert!(matches!(
+            refuse_dump(&data).unwrap_err().kind,
+            ErrorKind::BootloaderDump
+        ));
+
+        // One protocol word alone is a coincidence
+        let data = image(&[CMD_INIT, EXPECTED_STATUS]);
+        assert_eq!(refuse_dump(&data).unwrap(), Kind::Unknown);
+    }
+
+    #[test]
+    fn unknown() {
+        assert_eq!(classify(&image(&[])), Kind::Unknown);
+        assert_eq!(refuse_dump(&image(&[])).unwrap(), Kind::Unknown);
+        assert_eq!(Kind::Unknown.offset(Sonix::SN32F260), 0x200);
+    }
+}
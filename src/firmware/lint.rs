@@ -0,0 +1,483 @@
+use std::fmt::{Display, Formatter};
+
+use serde_json::{json, Value};
+
+use super::kind::{classify, Kind};
+use super::offset::{infer, Inference};
+use super::usb::Usb;
+use super::{problems, vector_table, FirmwareError};
+use crate::constants::BLOCK_SIZE;
+use crate::error::ErrorKind;
+use crate::flash::Sonix;
+use crate::layout::Layout;
+use crate::Result;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// One thing `firmware check` noticed about an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    /// A stable name for the kind of finding
+    pub code: &'static str,
+    pub message: String,
+    /// Why it matters
+    pub explanation: &'static str,
+}
+
+impl Finding {
+    fn new(
+        severity: Severity,
+        code: &'static str,
+        message: impl Into<String>,
+        explanation: &'static str,
+    ) -> Self {
+        Self {
+            severity,
+            code,
+            message: message.into(),
+            explanation,
+        }
+    }
+
+    fn from_problem(severity: Severity, chip: Option<Sonix>, problem: &FirmwareError) -> Self {
+        let (code, explanation) = match problem {
+            FirmwareError::TooSmall(_) => (
+                "too-small",
+                "the image can't even hold a vector table, it is probably not a firmware",
+            ),
+            FirmwareError::TooLarge { .. } => (
+                "too-large",
+                "the end of the image would be cut off or overwrite the bootloader",
+            ),
+            FirmwareError::StackPointer { .. } => (
+                "stack-pointer",
+                "the first word is loaded into the stack pointer on reset, the firmware crashes \
+                 on the first push if it isn't in ram",
+            ),
+            FirmwareError::MissingVector(_) => (
+                "missing-vector",
+                "the chip jumps to address 0 on reset or on the first fault",
+            ),
+            FirmwareError::NotThumb { .. } => (
+                "not-thumb",
+                "the Cortex-M0 only runs thumb code, jumping to an even address faults",
+            ),
+            FirmwareError::OutsideApplication { .. } => (
+                "outside-application",
+                "the vector points into the bootloader, past the end of flash or the image was \
+                 built for a different offset",
+            ),
+        };
+        let message = match chip {
+            Some(chip) => format!("{:?}: {}", chip, problem),
+            None => problem.to_string(),
+        };
+        Self::new(severity, code, message, explanation)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "severity": self.severity.to_string(),
+            "code": self.code,
+            "message": self.message,
+            "explanation": self.explanation,
+        })
+    }
+}
+
+/// Every [`Finding`] about an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// The chip the image was checked against, `None` if any supported chip will do
+    pub chip: Option<Sonix>,
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    pub fn count(&self, severity: Severity) -> usize {
+        self.findings
+            .iter()
+            .filter(|f| f.severity == severity)
+            .count()
+    }
+
+    /// Whether the image passes, with `strict` warnings count as failures
+    pub fn passed(&self, strict: bool) -> bool {
+        let worst = if strict {
+            Severity::Warning
+        } else {
+            Severity::Error
+        };
+        self.findings.iter().all(|f| f.severity < worst)
+    }
+
+    /// Fail with the number of errors and warnings unless the image [passed](Self::passed)
+    pub fn check(&self, strict: bool) -> Result<()> {
+        if self.passed(strict) {
+            return Ok(());
+        }
+        Err(ErrorKind::CheckFailed {
+            errors: self.count(Severity::Error),
+            warnings: self.count(Severity::Warning),
+        }
+        .into())
+    }
+
+    pub fn to_json(&self, strict: bool) -> Value {
+        json!({
+            "chip": self.chip.map(|chip| format!("{:?}", chip)),
+            "passed": self.passed(strict),
+            "errors": self.count(Severity::Error),
+            "warnings": self.count(Severity::Warning),
+            "findings": self.findings.iter().map(Finding::to_json).collect::<Vec<_>>(),
+        })
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut explained = Vec::new();
+        for finding in &self.findings {
+            writeln!(
+                f,
+                "{}[{}]: {}",
+                finding.severity, finding.code, finding.message
+            )?;
+            // One explanation per kind of finding is enough
+            if !explained.contains(&finding.code) {
+                explained.push(finding.code);
+                writeln!(f, "  = {}", finding.explanation)?;
+            }
+        }
+        writeln!(
+            f,
+            "{} errors, {} warnings",
+            self.count(Severity::Error),
+            self.count(Severity::Warning)
+        )
+    }
+}
+
+/// Check an image for everything that is wrong or suspicious
+///
+/// The image is placed at `base` if the file records it, otherwise at the chip's application
+/// offset. Without a `chip` problems that only some supported chips have are warnings, they are
+/// errors if no chip can run the image. `eeprom_size` overrides the chip's EEPROM size.
+pub fn lint(
+    data: &[u8],
+    base: Option<u32>,
+    chip: Option<Sonix>,
+    eeprom_size: Option<u32>,
+) -> Report {
+    let mut findings = Vec::new();
+    let blocks = data.len().div_ceil(BLOCK_SIZE);
+    findings.push(Finding::new(
+        Severity::Info,
+        "size",
+        format!("the image is {} bytes, {} blocks", data.len(), blocks),
+        "every block of 64 bytes is one write to the bootloader",
+    ));
+
+    if !data.is_empty() && data.iter().all(|b| *b == 0x00) {
+        findings.push(Finding::new(
+            Severity::Error,
+            "all-zero",
+            "the image is entirely 0x00",
+            "this is an empty file or a failed read, not a firmware",
+        ));
+    } else if !data.is_empty() && data.iter().all(|b| *b == 0xFF) {
+        findings.push(Finding::new(
+            Severity::Error,
+            "all-erased",
+            "the image is entirely 0xFF",
+            "this is what erased flash looks like, probably a dump of an empty chip",
+        ));
+    } else {
+        let trailing = data.iter().rev().take_while(|b| **b == 0xFF).count();
+        if trailing >= BLOCK_SIZE {
+            findings.push(Finding::new(
+                Severity::Warning,
+                "trailing-erased",
+                format!("the last {} bytes are all 0xFF", trailing),
+                "the image was probably padded to the size of the flash, flashing the padding \
+                 takes longer and may hit the end of the application region",
+            ));
+        }
+    }
+    if !data.len().is_multiple_of(BLOCK_SIZE) {
+        findings.push(Finding::new(
+            Severity::Warning,
+            "unaligned",
+            format!("the image is not a multiple of {} bytes", BLOCK_SIZE),
+            "the bootloader writes whole blocks, the last one is padded with 0xFF",
+        ));
+    }
+
+    let vectors = vector_table(data);
+    let kind = classify(data);
+    match kind {
+        Kind::BootloaderDump => findings.push(Finding::new(
+            Severity::Error,
+            "bootloader-dump",
+            "the image is a dump of the Sonix bootloader",
+            "it contains the flashing protocol and can't run as an application, flashing it \
+             would replace the firmware with a second copy of the bootloader",
+        )),
+        Kind::Unknown => findings.push(Finding::new(
+            Severity::Warning,
+            "unknown-kind",
+            "the image is neither QMK, stock firmware nor a jumploader",
+            "make sure it was built for this keyboard",
+        )),
+        kind => findings.push(Finding::new(
+            Severity::Info,
+            "kind",
+            format!("the image is {}", kind),
+            "QMK goes to the application offset of the chip, stock firmware and jumploaders to \
+             0x0",
+        )),
+    }
+
+    let usb = Usb::scan(data);
+    if let Some((vendor_id, product_id)) = usb.vid_pid() {
+        findings.push(match usb.known_name() {
+            Some(name) => Finding::new(
+                Severity::Info,
+                "usb-id",
+                format!(
+                    "the keyboard will show up as {:04x}:{:04x} ({})",
+                    vendor_id, product_id, name
+                ),
+                "the vid:pid comes from the usb device descriptor in the image",
+            ),
+            None => Finding::new(
+                Severity::Warning,
+                "unknown-usb-id",
+                format!(
+                    "the keyboard will show up as {:04x}:{:04x}, which is not a known keyboard",
+                    vendor_id, product_id
+                ),
+                "sflasher only reboots known keyboards into the bootloader, the next time it \
+                 has to be put into bootloader mode by hand",
+            ),
+        });
+    }
+
+    let inference = infer(data);
+    match (&inference, chip) {
+        (Inference::Base(inferred), Some(chip))
+            if *inferred != base.unwrap_or(kind.offset(chip)) =>
+        {
+            findings.push(Finding::new(
+                Severity::Error,
+                "linked-elsewhere",
+                format!(
+                    "the vector table says the image was linked for {:#x} but it goes to {:#x}",
+                    inferred,
+                    base.unwrap_or(kind.offset(chip))
+                ),
+                "a firmware flashed to a different address than it was linked for doesn't boot",
+            ));
+        }
+        (Inference::Base(inferred), _) => findings.push(Finding::new(
+            Severity::Info,
+            "linked-base",
+            format!(
+                "the vector table says the image was linked for {:#x}",
+                inferred
+            ),
+            "the handlers in the vector table only fit into the image at this offset",
+        )),
+        (Inference::Ambiguous(_), _) | (Inference::Unknown, _) => {}
+    }
+
+    match chip {
+        Some(chip) => {
+            let offset = base.unwrap_or(kind.offset(chip));
+            for problem in problems(data.len(), &vectors, chip, offset) {
+                findings.push(Finding::from_problem(Severity::Error, Some(chip), &problem));
+            }
+        }
+        None => {
+            let per_chip = Sonix::all().map(|chip| {
+                let offset = base.unwrap_or(kind.offset(chip));
+                (chip, problems(data.len(), &vectors, chip, offset))
+            });
+            // Problems every chip has are the image's fault, not the chip's
+            let (_, first) = &per_chip[0];
+            let common = first
+                .iter()
+                .filter(|p| per_chip.iter().all(|(_, problems)| problems.contains(p)))
+                .cloned()
+                .collect::<Vec<_>>();
+            for problem in &common {
+                findings.push(Finding::from_problem(Severity::Error, None, problem));
+            }
+            for (chip, problems) in &per_chip {
+                for problem in problems.iter().filter(|p| !common.contains(p)) {
+                    findings.push(Finding::from_problem(
+                        Severity::Warning,
+                        Some(*chip),
+                        problem,
+                    ));
+                }
+            }
+            if common.is_empty() && per_chip.iter().all(|(_, problems)| !problems.is_empty()) {
+                findings.push(Finding::new(
+                    Severity::Error,
+                    "no-chip",
+                    "the image does not run on any supported chip",
+                    "every chip has one of the problems above",
+                ));
+            }
+        }
+    }
+
+    let chips = match chip {
+        Some(chip) => vec![chip],
+        None => Sonix::all().to_vec(),
+    };
+    let layouts = chips
+        .into_iter()
+        .filter_map(|chip| {
+            let layout = Layout::new(chip, base.unwrap_or(kind.offset(chip)));
+            match eeprom_size.map(|size| layout.clone().with_eeprom_size(size)) {
+                Some(Err(e)) => {
+                    findings.push(Finding::new(
+                        Severity::Error,
+                        "eeprom-size",
+                        e.kind.to_string(),
+                        "the EEPROM has to fit between the offset and the end of flash",
+                    ));
+                    None
+                }
+                Some(Ok(layout)) => Some(layout),
+                None => Some(layout),
+            }
+        })
+        .collect::<Vec<_>>();
+    // Without a chip running into the EEPROM of some chips is fine as long as one has room
+    let overlap = if chip.is_some() || layouts.iter().all(|l| l.headroom(data.len()) < 0) {
+        Severity::Error
+    } else {
+        Severity::Warning
+    };
+    for layout in &layouts {
+        let headroom = layout.headroom(data.len());
+        findings.push(if headroom < 0 {
+            Finding::new(
+                overlap,
+                "eeprom-overlap",
+                format!(
+                    "{:?}: the image runs {} bytes into the emulated EEPROM at {:#x}",
+                    layout.chip, -headroom, layout.eeprom.start
+                ),
+                "QMK keeps the keymap and VIA settings in the last pages of flash and would \
+                 overwrite the end of the firmware with them",
+            )
+        } else {
+            Finding::new(
+                Severity::Info,
+                "headroom",
+                format!(
+                    "{:?}: {} bytes left before the emulated EEPROM at {:#x}",
+                    layout.chip, headroom, layout.eeprom.start
+                ),
+                "the firmware can grow this much before it runs into the keymap and VIA \
+                 settings",
+            )
+        });
+    }
+
+    Report { chip, findings }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firmware::offset::CPSID_I;
+
+    /// A QMK image linked at 0x200 with the ChibiOS reset handler
+    fn qmk(len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        data[..4].copy_from_slice(&0x2000_0700u32.to_le_bytes());
+        for vector in data[4..16].chunks_exact_mut(4) {
+            vector.copy_from_slice(&0x301u32.to_le_bytes());
+        }
+        data[0x100..0x102].copy_from_slice(&CPSID_I.to_le_bytes());
+        data
+    }
+
+    fn codes(report: &Report, severity: Severity) -> Vec<&'static str> {
+        report
+            .findings
+            .iter()
+            .filter(|f| f.severity == severity)
+            .map(|f| f.code)
+            .collect()
+    }
+
+    fn counts(report: &Report, strict: bool) -> Option<(usize, usize)> {
+        match report.check(strict).err()?.kind {
+            ErrorKind::CheckFailed { errors, warnings } => Some((errors, warnings)),
+            kind => panic!("Not a failed check: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn severity_order() {
+        assert!(Severity::Info < Severity::Warning);
+        assert!(Severity::Warning < Severity::Error);
+    }
+
+    #[test]
+    fn clean_image() {
+        let report = lint(&qmk(0x400), None, Some(Sonix::SN32F260), None);
+        assert_eq!(codes(&report, Severity::Error), Vec::<&str>::new());
+        assert_eq!(codes(&report, Severity::Warning), Vec::<&str>::new());
+        assert!(codes(&report, Severity::Info).contains(&"linked-base"));
+        assert!(report.passed(true));
+        assert_eq!(counts(&report, true), None);
+    }
+
+    #[test]
+    fn warnings_only_fail_strict() {
+        let report = lint(&qmk(0x3F0), None, Some(Sonix::SN32F260), None);
+        assert_eq!(codes(&report, Severity::Warning), ["unaligned"]);
+        assert_eq!(report.count(Severity::Error), 0);
+        assert!(report.passed(false));
+        assert!(!report.passed(true));
+        assert_eq!(counts(&report, false), None);
+        assert_eq!(counts(&report, true), Some((0, 1)));
+    }
+
+    #[test]
+    fn errors() {
+        // Linked for 0x200 but placed at 0x0
+        let report = lint(&qmk(0x400), Some(0), Some(Sonix::SN32F260), None);
+        let errors = codes(&report, Severity::Error);
+        assert_eq!(errors[0], "linked-elsewhere");
+        assert!(!report.passed(false));
+        assert_eq!(counts(&report, false), Some((errors.len(), 0)));
+
+        let report = lint(&[0; 0x400], None, None, None);
+        assert!(codes(&report, Severity::Error).contains(&"all-zero"));
+        assert!(!report.passed(false));
+    }
+}
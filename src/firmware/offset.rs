@@ -0,0 +1,126 @@
+use super::vector_table;
+use crate::flash::Sonix;
+
+/// `cpsid i`, the first instruction of the ChibiOS reset handler every QMK build uses
+pub const CPSID_I: u16 = 0xB672;
+/// `ldr r0, [pc, #imm]`, the first instruction of the Keil reset handler stock firmwares use
+pub const LDR_R0_PC: u8 = 0x48;
+
+/// The address an image was linked for, as far as the vector table tells
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inference {
+    /// Only this application offset makes sense
+    Base(u32),
+    /// Every vector fits with more than one of these offsets
+    Ambiguous(Vec<u32>),
+    /// No known offset fits the vectors
+    Unknown,
+}
+
+impl Inference {
+    pub fn base(&self) -> Option<u32> {
+        match self {
+            Inference::Base(base) => Some(*base),
+            _ => None,
+        }
+    }
+}
+
+/// Infer the link base of an image from the reset and exception vectors
+///
+/// Every handler has to be inside the image when it's placed at a candidate offset, the offsets
+/// chips use are the candidates. If that leaves more than one, the one where the reset handler
+/// starts like a known startup code wins.
+pub fn infer(data: &[u8]) -> Inference {
+    let vectors = vector_table(data);
+    let handlers = vectors[1..]
+        .iter()
+        .filter(|v| **v != 0)
+        .map(|v| v & !1)
+        .collect::<Vec<_>>();
+    if handlers.is_empty() {
+        return Inference::Unknown;
+    }
+    let mut candidates = Sonix::all().map(Sonix::offset).to_vec();
+    candidates.sort_unstable();
+    candidates.dedup();
+    let fits = candidates
+        .into_iter()
+        .filter(|base| {
+            handlers
+                .iter()
+                .all(|h| *h >= *base && ((h - base) as usize) < data.len())
+        })
+        .collect::<Vec<_>>();
+
+    match &fits[..] {
+        [] => Inference::Unknown,
+        [base] => Inference::Base(*base),
+        _ => {
+            let startup = fits
+                .iter()
+                .copied()
+                .filter(|base| {
+                    reset_instruction(data, *base)
+                        .is_some_and(|code| code == CPSID_I || (code >> 8) as u8 == LDR_R0_PC)
+                })
+                .collect::<Vec<_>>();
+            match startup[..] {
+                [base] => Inference::Base(base),
+                _ => Inference::Ambiguous(fits),
+            }
+        }
+    }
+}
+
+/// The first instruction of the reset handler if the image is placed at `base`
+pub fn reset_instruction(data: &[u8], base: u32) -> Option<u16> {
+    let at = (vector_table(data)[1] & !1).checked_sub(base)? as usize;
+    let code = data.get(at..at + 2)?;
+    Some(u16::from_le_bytes([code[0], code[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 0x400 byte image whose reset and exception vectors point at `handler`
+    fn image(handler: u32) -> Vec<u8> {
+        let mut data = vec![0; 0x400];
+        data[..4].copy_from_slice(&0x2000_0700u32.to_le_bytes());
+        for vector in data[4..16].chunks_exact_mut(4) {
+            vector.copy_from_slice(&handler.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn linked_at_0() {
+        // Placed at 0x200 the handler would be before the image
+        assert_eq!(infer(&image(0x101)), Inference::Base(0));
+    }
+
+    #[test]
+    fn linked_at_0x200() {
+        // Placed at 0 the handler would be past the end of the image
+        assert_eq!(infer(&image(0x501)), Inference::Base(0x200));
+
+        // Both fit, only at 0x200 the reset handler starts like ChibiOS
+        let mut data = image(0x301);
+        data[0x100..0x102].copy_from_slice(&CPSID_I.to_le_bytes());
+        assert_eq!(infer(&data), Inference::Base(0x200));
+        assert_eq!(reset_instruction(&data, 0x200), Some(CPSID_I));
+    }
+
+    #[test]
+    fn ambiguous() {
+        assert_eq!(infer(&image(0x301)), Inference::Ambiguous(vec![0, 0x200]));
+    }
+
+    #[test]
+    fn garbage() {
+        assert_eq!(infer(&image(0xFFFF_FFFF)), Inference::Unknown);
+        assert_eq!(infer(&[0; 0x400]), Inference::Unknown);
+        assert_eq!(infer(&[0xFF; 0x400]), Inference::Unknown);
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{Image, MAX_SPAN};
+use crate::error::ErrorKind;
+use crate::Result;
+
+pub const BLOCK_LEN: usize = 512;
+const MAGIC_START0: u32 = 0x0A32_4655;
+const MAGIC_START1: u32 = 0x9E5D_5157;
+const MAGIC_END: u32 = 0x0AB1_6F30;
+const PAYLOAD_MAX: u32 = 476;
+/// The block is meant for something other than main flash
+const FLAG_NOT_MAIN_FLASH: u32 = 0x0000_0001;
+/// The block is part of a file container instead of a flash image
+const FLAG_FILE_CONTAINER: u32 = 0x0000_1000;
+/// The file size field holds a family id
+const FLAG_FAMILY_ID: u32 = 0x0000_2000;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum Uf2Error {
+    #[error("the file size is not a multiple of 512")]
+    Truncated,
+    #[error("bad magic number")]
+    BadMagic,
+    #[error("payload of {0} bytes is larger than 476")]
+    PayloadTooLarge(u32),
+    #[error("the block has no payload")]
+    EmptyPayload,
+    #[error("block number {number} is not below the {total} blocks of the file")]
+    BlockOutOfRange { number: u32, total: u32 },
+    #[error("block number {0} appears twice")]
+    DuplicateBlock(u32),
+    #[error("file containers are not flash images")]
+    FileContainer,
+    #[error("blocks for families {0:#010x} and {1:#010x} are mixed, pick one with --uf2-family")]
+    MixedFamilies(u32, u32),
+    #[error("no blocks for family {0:#010x}")]
+    NoMatchingFamily(u32),
+    #[error("expected {expected} blocks but found {found}")]
+    MissingBlocks { expected: u32, found: usize },
+    #[error("data overlaps earlier data at {0:#x}")]
+    Overlap(u32),
+    #[error("no blocks for main flash")]
+    Empty,
+    #[error("image spans more than {MAX_SPAN} bytes")]
+    TooLarge,
+}
+
+fn error(block: usize, error: Uf2Error) -> crate::error::Error {
+    ErrorKind::InvalidUf2 { block, error }.into()
+}
+
+/// Whether the data starts with a UF2 block
+pub fn looks_like_uf2(data: &[u8]) -> bool {
+    data.len() >= BLOCK_LEN
+        && data[0..4] == MAGIC_START0.to_le_bytes()
+        && data[4..8] == MAGIC_START1.to_le_bytes()
+}
+
+/// A UF2 file reassembled into a flash image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uf2 {
+    pub image: Image,
+    /// The family id the image was assembled from, if the blocks name one
+    pub family: Option<u32>,
+}
+
+/// Reassemble the main flash blocks of a UF2 file
+///
+/// With a `target` family only blocks for that family and blocks without a family id are kept, a
+/// file that bundles images for several families is only refused without one.
+pub fn parse(data: &[u8], target: Option<u32>) -> Result<Uf2> {
+    if !data.len().is_multiple_of(BLOCK_LEN) {
+        return Err(error(data.len() / BLOCK_LEN, Uf2Error::Truncated));
+    }
+    let mut chunks = BTreeMap::<u32, Vec<u8>>::new();
+    let mut main = 0;
+    let mut family = None;
+    let mut expected = None;
+    let mut numbers = BTreeSet::new();
+
+    for (i, block) in data.chunks_exact(BLOCK_LEN).enumerate() {
+        let word =
+            |at: usize| u32::from_le_bytes(block[at..at + 4].try_into().expect("Slice of 4"));
+        if word(0) != MAGIC_START0 || word(4) != MAGIC_START1 || word(508) != MAGIC_END {
+            return Err(error(i, Uf2Error::BadMagic));
+        }
+        let (flags, address, size) = (word(8), word(12), word(16));
+        if flags & FLAG_FILE_CONTAINER != 0 {
+            return Err(error(i, Uf2Error::FileContainer));
+        }
+        // Block numbers and counts are per family, blocks of other families are not ours to count
+        if flags & FLAG_FAMILY_ID != 0 {
+            let id = word(28);
+            match (target, family) {
+                (Some(target), _) if target != id => continue,
+                (None, Some(f)) if f != id => return Err(error(i, Uf2Error::MixedFamilies(f, id))),
+                _ => family = Some(id),
+            }
+        }
+        let (number, total) = (word(20), word(24));
+        if number >= total {
+            return Err(error(i, Uf2Error::BlockOutOfRange { number, total }));
+        }
+        if !numbers.insert(number) {
+            return Err(error(i, Uf2Error::DuplicateBlock(number)));
+        }
+        expected = Some(total);
+        // Blocks not meant for main flash still count towards the number of blocks
+        if flags & FLAG_NOT_MAIN_FLASH != 0 {
+            continue;
+        }
+        main += 1;
+        if size > PAYLOAD_MAX {
+            return Err(error(i, Uf2Error::PayloadTooLarge(size)));
+        }
+        if size == 0 {
+            return Err(error(i, Uf2Error::EmptyPayload));
+        }
+
+        let end = address
+            .checked_add(size)
+            .ok_or_else(|| error(i, Uf2Error::TooLarge))?;
+        if let Some((start, chunk)) = chunks.range(..end).next_back() {
+            if *start as u64 + chunk.len() as u64 > address as u64 {
+                return Err(error(i, Uf2Error::Overlap(address)));
+            }
+        }
+        match chunks.entry(address) {
+            Entry::Occupied(_) => return Err(error(i, Uf2Error::Overlap(address))),
+            Entry::Vacant(entry) => entry.insert(block[32..32 + size as usize].to_vec()),
+        };
+    }
+
+    let blocks = data.len() / BLOCK_LEN;
+    if main == 0 {
+        return Err(error(
+            blocks,
+            target.map_or(Uf2Error::Empty, Uf2Error::NoMatchingFamily),
+        ));
+    }
+    let found = numbers.len();
+    if let Some(expected) = expected.filter(|e| *e as usize != found) {
+        return Err(error(blocks, Uf2Error::MissingBlocks { expected, found }));
+    }
+    let image = Image::assemble(&chunks).ok_or_else(|| error(blocks, Uf2Error::TooLarge))?;
+    Ok(Uf2 { image, family })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A main flash block of `len` bytes of `fill` at `address`
+    fn block(
+        address: u32,
+        number: u32,
+        total: u32,
+        family: Option<u32>,
+        len: u32,
+        fill: u8,
+    ) -> Vec<u8> {
+        let mut data = vec![0; BLOCK_LEN];
+        let mut put = |at: usize, word: u32| data[at..at + 4].copy_from_slice(&word.to_le_bytes());
+        put(0, MAGIC_START0);
+        put(4, MAGIC_START1);
+        put(8, if family.is_some() { FLAG_FAMILY_ID } else { 0 });
+        put(12, address);
+        put(16, len);
+        put(20, number);
+        put(24, total);
+        put(28, family.unwrap_or(0));
+        put(508, MAGIC_END);
+        data[32..32 + len as usize].fill(fill);
+        data
+    }
+
+    fn uf2_error(result: Result<Uf2>) -> Uf2Error {
+        match result.map(|_| ()).unwrap_err().kind {
+            ErrorKind::InvalidUf2 { error, .. } => error,
+            kind => panic!("Not a UF2 error: {:?}", kind),
+        }
+    }
+
+    const A: u32 = 0x1111_1111;
+    const B: u32 = 0x2222_2222;
+
+    #[test]
+    fn single_family() {
+        let data = [
+            block(0x200, 0, 2, Some(A), 256, 1),
+            block(0x300, 1, 2, Some(A), 256, 2),
+        ]
+        .concat();
+        let uf2 = parse(&data, None).unwrap();
+        assert_eq!(uf2.family, Some(A));
+        assert_eq!(uf2.image.base, 0x200);
+        assert_eq!(uf2.image.data.len(), 512);
+        assert!(uf2.image.data[..256].iter().all(|b| *b == 1));
+        assert!(uf2.image.data[256..].iter().all(|b| *b == 2));
+    }
+
+    #[test]
+    fn multiple_families() {
+        // Each family numbers and counts its own blocks
+        let data = [
+            block(0x200, 0, 2, Some(A), 256, 1),
+            block(0x1000_0000, 0, 1, Some(B), 256, 3),
+            block(0x300, 1, 2, Some(A), 256, 2),
+        ]
+        .concat();
+        let uf2 = parse(&data, Some(A)).unwrap();
+        assert_eq!(
+            (uf2.family, uf2.image.base, uf2.image.data.len()),
+            (Some(A), 0x200, 512)
+        );
+        let uf2 = parse(&data, Some(B)).unwrap();
+        assert_eq!(
+            (uf2.family, uf2.image.base, uf2.image.data.len()),
+            (Some(B), 0x1000_0000, 256)
+        );
+
+        assert_eq!(uf2_error(parse(&data, None)), Uf2Error::MixedFamilies(A, B));
+        assert_eq!(
+            uf2_error(parse(&data, Some(0x3333_3333))),
+            Uf2Error::NoMatchingFamily(0x3333_3333)
+        );
+    }
+
+    #[test]
+    fn missing_blocks() {
+        let data = [
+            block(0x200, 0, 3, None, 256, 1),
+            block(0x300, 1, 3, None, 256, 2),
+        ]
+        .concat();
+        assert_eq!(
+            uf2_error(parse(&data, None)),
+            Uf2Error::MissingBlocks {
+                expected: 3,
+                found: 2
+            }
+        );
+    }
+
+    #[test]
+    fn bad_block_numbers() {
+        let data = [
+            block(0x200, 0, 2, None, 256, 1),
+            block(0x300, 0, 2, None, 256, 2),
+        ]
+        .concat();
+        assert_eq!(uf2_error(parse(&data, None)), Uf2Error::DuplicateBlock(0));
+
+        let data = [
+            block(0x200, 0, 2, None, 256, 1),
+            block(0x300, 2, 2, None, 256, 2),
+        ]
+        .concat();
+        assert_eq!(
+            uf2_error(parse(&data, None)),
+            Uf2Error::BlockOutOfRange {
+                number: 2,
+                total: 2
+            }
+        );
+    }
+
+    #[test]
+    fn overlaps() {
+        let data = [
+            block(0x200, 0, 2, None, 256, 1),
+            block(0x280, 1, 2, None, 256, 2),
+        ]
+        .concat();
+        assert_eq!(uf2_error(parse(&data, None)), Uf2Error::Overlap(0x280));
+
+        let data = [
+            block(0x200, 0, 2, None, 256, 1),
+            block(0x200, 1, 2, None, 256, 2),
+        ]
+        .concat();
+        assert_eq!(uf2_error(parse(&data, None)), Uf2Error::Overlap(0x200));
+
+        let data = [
+            block(0x200, 0, 2, None, 256, 1),
+            block(0x300, 1, 2, None, 0, 2),
+        ]
+        .concat();
+        assert_eq!(uf2_error(parse(&data, None)), Uf2Error::EmptyPayload);
+    }
+}
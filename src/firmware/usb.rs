@@ -0,0 +1,188 @@
+use std::fmt::{Display, Formatter};
+
+use crate::devices::{Mode, Normal};
+
+const DEVICE_DESCRIPTOR: u8 = 0x01;
+const STRING_DESCRIPTOR: u8 = 0x03;
+const DEVICE_DESCRIPTOR_LEN: usize = 18;
+/// Shorter strings show up in random data all the time
+const MIN_CHARS: usize = 2;
+
+/// The fields of a usb device descriptor that identify the keyboard
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub release: u16,
+}
+
+/// Whether 18 bytes look like a device descriptor, the usb version, packet size and number of
+/// configurations only take a few values in practice
+fn device_descriptor(d: &[u8]) -> Option<DeviceDescriptor> {
+    let u16_at = |at: usize| u16::from_le_bytes([d[at], d[at + 1]]);
+    let plausible = d[0] as usize == DEVICE_DESCRIPTOR_LEN
+        && d[1] == DEVICE_DESCRIPTOR
+        && matches!(u16_at(2), 0x0100 | 0x0110 | 0x0200)
+        && matches!(d[7], 8 | 16 | 32 | 64)
+        && (1..=4).contains(&d[17])
+        && !matches!(u16_at(8), 0x0000 | 0xFFFF);
+    plausible.then(|| DeviceDescriptor {
+        usb_version: u16_at(2),
+        vendor_id: u16_at(8),
+        product_id: u16_at(10),
+        release: u16_at(12),
+    })
+}
+
+/// The text of every usb string descriptor in the image, only ascii text is recognised
+pub fn string_descriptors(data: &[u8]) -> Vec<String> {
+    let mut found = Vec::new();
+    let mut i = 0;
+    while i + 2 < data.len() {
+        let len = data[i] as usize;
+        let text = data.get(i + 2..i + len).unwrap_or_default();
+        let ascii = text
+            .chunks_exact(2)
+            .all(|c| (0x20..0x7F).contains(&c[0]) && c[1] == 0);
+        if data[i + 1] == STRING_DESCRIPTOR
+            && len.is_multiple_of(2)
+            && len >= 2 + MIN_CHARS * 2
+            && text.len() == len - 2
+            && ascii
+        {
+            found.push(text.chunks_exact(2).map(|c| c[0] as char).collect());
+            i += len;
+        } else {
+            i += 1;
+        }
+    }
+    found
+}
+
+/// How the keyboard will show up on usb after flashing the image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Usb {
+    pub device: Option<DeviceDescriptor>,
+    /// QMK keeps the manufacturer string in front of the product string, so these are the first
+    /// and second string descriptor
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+impl Usb {
+    pub fn scan(data: &[u8]) -> Self {
+        let device = data
+            .windows(DEVICE_DESCRIPTOR_LEN)
+            .find_map(device_descriptor);
+        let mut strings = string_descriptors(data).into_iter();
+        Self {
+            device,
+            manufacturer: strings.next(),
+            product: strings.next(),
+        }
+    }
+
+    pub fn vid_pid(&self) -> Option<(u16, u16)> {
+        self.device.as_ref().map(|d| (d.vendor_id, d.product_id))
+    }
+
+    /// The name of the keyboard if sflasher knows its vid:pid
+    pub fn known_name(&self) -> Option<&'static str> {
+        Normal::devices()
+            .get(&self.vid_pid()?)
+            .map(|entry| entry.name.as_str())
+    }
+}
+
+impl Display for Usb {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.device {
+            Some(d) => write!(
+                f,
+                "{:04x}:{:04x} release {:x} ({})",
+                d.vendor_id,
+                d.product_id,
+                d.release,
+                self.known_name().unwrap_or("not a known keyboard")
+            )?,
+            None => write!(f, "no device descriptor found")?,
+        }
+        if let Some(manufacturer) = &self.manufacturer {
+            write!(f, ", manufacturer {:?}", manufacturer)?;
+        }
+        if let Some(product) = &self.product {
+            write!(f, ", product {:?}", product)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A device descriptor for usb 2.0 with a packet size of 64 and one configuration
+    fn descriptor(vid: u16, pid: u16) -> Vec<u8> {
+        let mut d = vec![18, DEVICE_DESCRIPTOR, 0x00, 0x02, 0, 0, 0, 64];
+        d.extend_from_slice(&vid.to_le_bytes());
+        d.extend_from_slice(&pid.to_le_bytes());
+        d.extend_from_slice(&[0x01, 0x00, 1, 2, 0, 1]);
+        d
+    }
+
+    /// A string descriptor with the text in UTF-16
+    fn string(text: &str) -> Vec<u8> {
+        let mut d = vec![2 + 2 * text.len() as u8, STRING_DESCRIPTOR];
+        d.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        d
+    }
+
+    #[test]
+    fn vid_pid() {
+        let data = [&[0xFF; 7][..], &descriptor(0x0c45, 0x652f), &[0; 5]].concat();
+        let usb = Usb::scan(&data);
+        assert_eq!(usb.vid_pid(), Some((0x0c45, 0x652f)));
+        assert_eq!(
+            usb.device.map(|d| (d.usb_version, d.release)),
+            Some((0x0200, 0x0001))
+        );
+    }
+
+    #[test]
+    fn strings() {
+        let data = [
+            &[0x03, 0x03, 0xAA][..],
+            &string("Glorious"),
+            &[0; 3],
+            &string("GMMK"),
+            // A single character is too short to be a real string
+            &string("X"),
+        ]
+        .concat();
+        assert_eq!(string_descriptors(&data), ["Glorious", "GMMK"]);
+        let usb = Usb::scan(&data);
+        assert_eq!(usb.manufacturer.as_deref(), Some("Glorious"));
+        assert_eq!(usb.product.as_deref(), Some("GMMK"));
+        assert_eq!(usb.device, None);
+    }
+
+    #[test]
+    fn truncated() {
+        let d = descriptor(0x0c45, 0x652f);
+        assert_eq!(Usb::scan(&d[..17]).device, None);
+
+        // The length says 8 characters but the image ends after 4
+        let data = &string("Keyboard")[..10];
+        assert!(string_descriptors(data).is_empty());
+    }
+
+    #[test]
+    fn known_name() {
+        let usb = Usb::scan(&descriptor(0x0c45, 0x652f));
+        assert_eq!(usb.known_name(), Some("Glorious GMMK / Tecware Phantom"));
+        let usb = Usb::scan(&descriptor(0x1234, 0x5678));
+        assert_eq!(usb.known_name(), None);
+        assert_eq!(Usb::scan(&[]).known_name(), None);
+    }
+}
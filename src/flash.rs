@@ -1,10 +1,22 @@
+use std::ops::Range;
+
 use hidapi::DeviceInfo;
 
+use crate::constants::BLOCK_SIZE;
 use crate::error::{Error, ErrorKind};
+use crate::firmware::kind::Kind;
+use crate::firmware::offset::Inference;
+use crate::layout::Layout;
 
 pub struct FlashingOptions {
     pub device_type: Sonix,
     pub offset: Option<u32>,
+    /// Pads the last block of the image, 0xFF matches erased flash
+    pub fill: u8,
+    /// Overrides the EEPROM size of the chip for keyboards that use a different one
+    pub eeprom_size: Option<u32>,
+    /// Erase the emulated EEPROM along with the firmware
+    pub wipe_eeprom: bool,
 }
 
 impl FlashingOptions {
@@ -19,39 +31,296 @@ impl FlashingOptions {
         self.offset = offset;
         self
     }
+    /// Without an explicit offset use the one images of this kind expect
+    pub fn with_kind(&mut self, kind: Kind) -> &mut Self {
+        if self.offset.is_none() {
+            self.offset = Some(kind.offset(self.device_type));
+        }
+        self
+    }
+    pub fn with_eeprom_size(&mut self, size: Option<u32>) -> &mut Self {
+        self.eeprom_size = size;
+        self
+    }
+    pub fn with_wipe_eeprom(&mut self, wipe: bool) -> &mut Self {
+        self.wipe_eeprom = wipe;
+        self
+    }
+
+    pub fn layout(&self) -> Result<Layout, Error> {
+        let layout = Layout::new(self.device_type, self.offset());
+        match self.eeprom_size {
+            Some(size) => layout.with_eeprom_size(size),
+            None => Ok(layout),
+        }
+    }
+
+    /// Writing `len` bytes at the offset must leave the emulated EEPROM alone
+    ///
+    /// QMK would otherwise write its settings over the end of the firmware, wiping the EEPROM
+    /// doesn't change that.
+    pub fn check_eeprom(&self, len: usize) -> Result<(), Error> {
+        self.layout()?.check(len)
+    }
+
+    /// Use the address a firmware file was built for
+    ///
+    /// It has to agree with the explicit offset or, without one, the application offset of the
+    /// chip.
+    pub fn with_base(&mut self, base: Option<u32>) -> Result<&mut Self, Error> {
+        if let Some(firmware) = base {
+            let offset = self.offset();
+            if firmware != offset {
+                return Err(ErrorKind::OffsetMismatch { firmware, offset }.into());
+            }
+            self.offset = Some(firmware);
+        }
+        Ok(self)
+    }
+
+    /// Writing `len` bytes at the offset must not touch the protected region of the chip
+    pub fn check_protected(&self, len: usize) -> Result<(), Error> {
+        let start = self.offset();
+        let end = start.saturating_add((len.div_ceil(BLOCK_SIZE) * BLOCK_SIZE) as u32);
+        let protected = self.device_type.protected();
+        if start < protected.end && protected.start < end {
+            return Err(ErrorKind::ProtectedRegion {
+                start,
+                end,
+                protected_start: protected.start,
+                protected_end: protected.end,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// The offset has to agree with the link base inferred from the vector table, if there is
+    /// only one
+    pub fn check_inferred(&self, inference: &Inference) -> Result<(), Error> {
+        match inference.base() {
+            Some(inferred) if inferred != self.offset() => Err(ErrorKind::InferredOffsetMismatch {
+                inferred,
+                offset: self.offset(),
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl From<Sonix> for FlashingOptions {
+    fn from(device_type: Sonix) -> Self {
+        Self {
+            device_type,
+            offset: None,
+            fill: 0xFF,
+            eeprom_size: None,
+            wipe_eeprom: false,
+        }
+    }
 }
 
 impl TryFrom<&DeviceInfo> for FlashingOptions {
     type Error = Error;
 
     fn try_from(device_info: &DeviceInfo) -> Result<Self, Self::Error> {
-        let device_type = Sonix::try_from(device_info.product_id())?;
-        Ok(Self {
-            device_type,
-            offset: None,
-        })
+        Ok(Sonix::try_from(device_info.product_id())?.into())
     }
 }
 
-#[repr(u16)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Sonix {
-    SN32F248 = 0x7040,
-    SN32F248B = 0x7900,
-    SN32F260 = 0x7010,
+    SN32F248,
+    SN32F248B,
+    SN32F260,
+}
+
+/// Everything known about one chip, every other place reads it from here
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChipInfo {
+    /// The product id of the bootloader, the vendor id is always
+    /// [`SONIX_VID`](crate::constants::SONIX_VID)
+    pub pid: u16,
+    /// The name the chip is sold under
+    pub name: &'static str,
+    /// The amount of flash the bootloader lets us write to
+    pub flash_size: usize,
+    /// The ram, the initial stack pointer has to be inside or at the end of it
+    pub ram: Range<u32>,
+    /// Where QMK goes
+    pub offset: u32,
+    /// Where the firmware the keyboard shipped with starts
+    pub stock_offset: u32,
+    /// The part of the flash that holds the bootloader
+    ///
+    /// Every write into it needs `--overwrite-bootloader`, including stock firmware and
+    /// jumploaders that start inside it.
+    pub protected: Range<u32>,
+    /// The smallest unit the flash is erased in
+    pub page_size: usize,
+    /// The size of a feature report, one block of firmware per report
+    pub report_size: usize,
+    /// The size of the EEPROM QMK emulates at the end of flash
+    pub eeprom_size: u32,
 }
 
+const SN32F248: ChipInfo = ChipInfo {
+    pid: 0x7900,
+    name: "SN32F248",
+    flash_size: 64 * 1024,
+    ram: 0x2000_0000..0x2000_2000,
+    offset: 0x0,
+    stock_offset: 0x0,
+    protected: 0x0..0x0,
+    page_size: 64,
+    report_size: 64,
+    eeprom_size: 0x800,
+};
+
+const SN32F248B: ChipInfo = ChipInfo {
+    pid: 0x7040,
+    name: "SN32F248B",
+    flash_size: 64 * 1024,
+    ram: 0x2000_0000..0x2000_2000,
+    offset: 0x0,
+    stock_offset: 0x0,
+    protected: 0x0..0x0,
+    page_size: 64,
+    report_size: 64,
+    eeprom_size: 0x800,
+};
+
+const SN32F260: ChipInfo = ChipInfo {
+    pid: 0x7010,
+    name: "SN32F268F",
+    flash_size: 30 * 1024,
+    ram: 0x2000_0000..0x2000_0800,
+    offset: 0x200,
+    stock_offset: 0x0,
+    protected: 0x0..0x200,
+    page_size: 64,
+    report_size: 64,
+    eeprom_size: 0x400,
+};
+
+// The registry has to be consistent with itself and with how flashing works
+const _: () = {
+    let chips = Sonix::all();
+    let mut i = 0;
+    while i < chips.len() {
+        let info = chips[i].info();
+        assert!(chips[i] as usize == i, "Sonix::all() is out of order");
+        let mut j = i + 1;
+        while j < chips.len() {
+            assert!(info.pid != chips[j].info().pid, "two chips share a pid");
+            j += 1;
+        }
+        assert!(
+            info.report_size == BLOCK_SIZE,
+            "flashing sends BLOCK_SIZE reports"
+        );
+        assert!(info.flash_size.is_multiple_of(info.page_size));
+        assert!(info.ram.start < info.ram.end);
+        assert!(info.protected.start <= info.protected.end);
+        assert!(
+            info.protected.end <= info.offset,
+            "QMK would overwrite the bootloader"
+        );
+        assert!((info.offset as usize).is_multiple_of(info.report_size));
+        assert!((info.eeprom_size as usize).is_multiple_of(info.page_size));
+        assert!(info.offset as usize + (info.eeprom_size as usize) < info.flash_size);
+        i += 1;
+    }
+};
+
 impl Sonix {
-    pub const fn offset(self) -> u32 {
+    pub const fn info(self) -> &'static ChipInfo {
         match self {
-            Sonix::SN32F248 => 0x0,
-            Sonix::SN32F248B => 0x0,
-            Sonix::SN32F260 => 0x200,
+            Sonix::SN32F248 => &SN32F248,
+            Sonix::SN32F248B => &SN32F248B,
+            Sonix::SN32F260 => &SN32F260,
         }
     }
 
+    /// The chip whose bootloader uses this product id
+    pub fn from_pid(pid: u16) -> Option<Self> {
+        Sonix::all().into_iter().find(|chip| chip.pid() == pid)
+    }
+
+    pub const fn offset(self) -> u32 {
+        self.info().offset
+    }
+
+    /// Where the firmware the keyboard shipped with starts
+    pub const fn stock_offset(self) -> u32 {
+        self.info().stock_offset
+    }
+
     pub const fn pid(self) -> u16 {
-        self as u16
+        self.info().pid
+    }
+
+    /// The name the chip is sold under, the bootloader shows up as this
+    pub const fn name(self) -> &'static str {
+        self.info().name
+    }
+
+    /// The amount of flash the bootloader lets us write to
+    pub const fn flash_size(self) -> usize {
+        self.info().flash_size
+    }
+
+    /// The size of the EEPROM QMK emulates at the end of flash
+    pub const fn eeprom_size(self) -> u32 {
+        self.info().eeprom_size
+    }
+
+    /// The ram, the initial stack pointer has to be inside or at the end of it
+    pub const fn ram(self) -> Range<u32> {
+        let ram = &self.info().ram;
+        ram.start..ram.end
+    }
+
+    /// The part of the flash that holds the bootloader and must never be written to
+    pub const fn protected(self) -> Range<u32> {
+        let protected = &self.info().protected;
+        protected.start..protected.end
+    }
+
+    pub const fn page_size(self) -> usize {
+        self.info().page_size
+    }
+
+    pub const fn report_size(self) -> usize {
+        self.info().report_size
+    }
+
+    pub const fn all() -> [Sonix; 3] {
+        [Sonix::SN32F248, Sonix::SN32F248B, Sonix::SN32F260]
+    }
+}
+
+impl From<crate::cli::Chip> for Sonix {
+    fn from(chip: crate::cli::Chip) -> Self {
+        match chip {
+            crate::cli::Chip::SN32F248 => Sonix::SN32F248,
+            crate::cli::Chip::SN32F248B => Sonix::SN32F248B,
+            crate::cli::Chip::SN32F260 => Sonix::SN32F260,
+        }
+    }
+}
+
+impl std::str::FromStr for Sonix {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Sonix::all()
+            .into_iter()
+            .find(|chip| {
+                format!("{:?}", chip).eq_ignore_ascii_case(s) || chip.name().eq_ignore_ascii_case(s)
+            })
+            .ok_or_else(|| ErrorKind::InvalidIdentifier(s.into()).into())
     }
 }
 
@@ -59,11 +328,65 @@ impl TryFrom<u16> for Sonix {
     type Error = Error;
 
     fn try_from(value: u16) -> Result<Self, Self::Error> {
-        match value {
-            0x7040 => Ok(Sonix::SN32F248),
-            0x7900 => Ok(Sonix::SN32F248B),
-            0x7010 => Ok(Sonix::SN32F260),
-            _ => Err(ErrorKind::InvalidDevice.into()),
+        Sonix::from_pid(value).ok_or_else(|| ErrorKind::InvalidDevice.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::ValueEnum;
+
+    use super::*;
+    use crate::cli::Chip;
+    use crate::constants::SONIX_VID;
+    use crate::devices::{Bootloader, Mode};
+
+    #[test]
+    fn pids() {
+        assert_eq!(Sonix::from_pid(0x7040), Some(Sonix::SN32F248B));
+        assert_eq!(Sonix::from_pid(0x7900), Some(Sonix::SN32F248));
+        assert_eq!(Sonix::from_pid(0x7010), Some(Sonix::SN32F260));
+        assert_eq!(Sonix::SN32F260.name(), "SN32F268F");
+        assert_eq!(Sonix::from_pid(0x652f), None);
+    }
+
+    #[test]
+    fn stock_and_jumploader_writes_are_protected() {
+        for kind in [Kind::Stock, Kind::Jumploader] {
+            let mut options = FlashingOptions::from(Sonix::SN32F260);
+            options.with_kind(kind);
+            assert!(options.check_protected(0x100).is_err(), "{}", kind);
+
+            let mut options = FlashingOptions::from(Sonix::SN32F248B);
+            options.with_kind(kind);
+            assert!(options.check_protected(0x100).is_ok(), "{}", kind);
+        }
+    }
+
+    #[test]
+    fn cli_chips_match_the_registry() {
+        for chip in Chip::value_variants() {
+            let name = chip.to_possible_value().unwrap();
+            let sonix = Sonix::from(*chip);
+            assert_eq!(name.get_name(), format!("{:?}", sonix).to_lowercase());
+            assert_eq!(name.get_name().parse::<Sonix>().unwrap(), sonix);
+            assert_eq!(Sonix::from_pid(sonix.pid()), Some(sonix));
+        }
+        assert_eq!(Chip::value_variants().len(), Sonix::all().len());
+        assert_eq!(
+            Chip::from_str("sn32f268f", true).map(Sonix::from),
+            Ok(Sonix::SN32F260)
+        );
+    }
+
+    #[test]
+    fn bootloader_devices_match_the_registry() {
+        let devices = Bootloader::devices();
+        assert_eq!(devices.len(), Sonix::all().len());
+        for chip in Sonix::all() {
+            let entry = &devices[&(SONIX_VID, chip.pid())];
+            assert_eq!(entry.chip, Some(chip));
+            assert_eq!(entry.name, chip.name());
         }
     }
 }
@@ -0,0 +1,334 @@
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+use serde_json::{json, Value};
+
+use crate::constants::BLOCK_SIZE;
+use crate::error::{Error, ErrorKind};
+use crate::flash::Sonix;
+
+/// The number of characters of the bar `layout` draws
+const BAR_WIDTH: usize = 64;
+
+/// How the flash of a keyboard is divided
+///
+/// The bootloader sits at the start, QMK emulates an EEPROM in the last pages of flash for the
+/// keymap and VIA settings, the firmware goes in between.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub chip: Sonix,
+    pub bootloader: Range<u32>,
+    /// Where the firmware may go, from the offset up to the emulated EEPROM
+    pub application: Range<u32>,
+    pub eeprom: Range<u32>,
+}
+
+impl Layout {
+    /// The layout of `chip` with the firmware at `offset` and the default EEPROM size
+    pub fn new(chip: Sonix, offset: u32) -> Self {
+        let end = chip.flash_size() as u32;
+        let eeprom = end.saturating_sub(chip.eeprom_size())..end;
+        Self {
+            chip,
+            bootloader: chip.protected(),
+            application: offset..eeprom.start.max(offset),
+            eeprom,
+        }
+    }
+
+    /// Use the EEPROM size of a keyboard that doesn't use the chip's default
+    pub fn with_eeprom_size(mut self, size: u32) -> Result<Self, Error> {
+        let end = self.chip.flash_size() as u32;
+        if size > end.saturating_sub(self.application.start) {
+            return Err(ErrorKind::InvalidEepromSize {
+                size,
+                chip: self.chip,
+            }
+            .into());
+        }
+        self.eeprom = end - size..end;
+        self.application.end = self.eeprom.start;
+        Ok(self)
+    }
+
+    /// Bytes left between the end of an image of `len` bytes and the EEPROM, negative if it
+    /// runs into it
+    pub fn headroom(&self, len: usize) -> i64 {
+        self.application.end as i64 - self.application.start as i64 - padded(len) as i64
+    }
+
+    /// An image of `len` bytes at the offset must end before the EEPROM
+    pub fn check(&self, len: usize) -> Result<(), Error> {
+        let start = self.application.start;
+        let end = start.saturating_add(padded(len) as u32);
+        if start < self.eeprom.end && self.eeprom.start < end {
+            return Err(ErrorKind::EepromOverlap {
+                start,
+                end,
+                eeprom_start: self.eeprom.start,
+                eeprom_end: self.eeprom.end,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// What a part of the flash is used for
+///
+/// Ordered by how important it is to see it in the bar, a character of the bar that covers more
+/// than one region shows the most important one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RegionKind {
+    Free,
+    /// Between the bootloader and the offset, e.g. where a jumploader goes
+    Unused,
+    Firmware,
+    Eeprom,
+    Bootloader,
+    /// The firmware runs into the bootloader or the EEPROM here
+    Overlap,
+}
+
+impl RegionKind {
+    pub fn symbol(self) -> char {
+        match self {
+            RegionKind::Free => '.',
+            RegionKind::Unused => '-',
+            RegionKind::Firmware => '#',
+            RegionKind::Eeprom => 'E',
+            RegionKind::Bootloader => 'B',
+            RegionKind::Overlap => '!',
+        }
+    }
+}
+
+impl Display for RegionKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RegionKind::Free => "free",
+            RegionKind::Unused => "unused",
+            RegionKind::Firmware => "firmware",
+            RegionKind::Eeprom => "eeprom",
+            RegionKind::Bootloader => "bootloader",
+            RegionKind::Overlap => "overlap",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub range: Range<u32>,
+}
+
+/// The flash map `sflasher layout` prints, a [`Layout`] with an image of `firmware` bytes placed
+/// at the offset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Map {
+    pub layout: Layout,
+    pub firmware: Option<usize>,
+    /// Every byte of flash belongs to exactly one region, in order of address
+    pub regions: Vec<Region>,
+}
+
+impl Map {
+    pub fn new(layout: Layout, firmware: Option<usize>) -> Self {
+        let flash_end = layout.chip.flash_size() as u32;
+        let offset = layout.application.start;
+        let firmware_end = firmware
+            .map(|len| offset.saturating_add(padded(len) as u32).min(flash_end))
+            .unwrap_or(offset);
+        // A stock image at 0x0 on the SN32F260 starts inside the bootloader
+        let bootloader = &layout.bootloader;
+        let overwritten = match offset.max(bootloader.start)..firmware_end.min(bootloader.end) {
+            range if range.is_empty() => bootloader.end..bootloader.end,
+            range => range,
+        };
+        let boundaries = [
+            (RegionKind::Bootloader, overwritten.start),
+            (RegionKind::Overlap, overwritten.end),
+            (RegionKind::Bootloader, bootloader.end),
+            (RegionKind::Unused, offset.min(layout.eeprom.start)),
+            (RegionKind::Firmware, firmware_end.min(layout.eeprom.start)),
+            (RegionKind::Free, layout.eeprom.start),
+            (RegionKind::Overlap, firmware_end),
+            (RegionKind::Eeprom, flash_end),
+        ];
+        let mut regions = Vec::new();
+        let mut start = 0;
+        for (kind, end) in boundaries {
+            if end > start {
+                regions.push(Region {
+                    kind,
+                    range: start..end,
+                });
+                start = end;
+            }
+        }
+        Self {
+            layout,
+            firmware,
+            regions,
+        }
+    }
+
+    /// One character per [`BAR_WIDTH`]th of the flash
+    pub fn bar(&self) -> String {
+        let size = self.layout.chip.flash_size();
+        (0..BAR_WIDTH)
+            .map(|i| {
+                let cell = (i * size / BAR_WIDTH) as u32..((i + 1) * size / BAR_WIDTH) as u32;
+                self.regions
+                    .iter()
+                    .filter(|r| r.range.start < cell.end && cell.start < r.range.end)
+                    .map(|r| r.kind)
+                    .max()
+                    .unwrap_or(RegionKind::Free)
+                    .symbol()
+            })
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let regions: Vec<_> = self
+            .regions
+            .iter()
+            .map(|r| {
+                json!({
+                    "kind": r.kind.to_string(),
+                    "start": r.range.start,
+                    "end": r.range.end,
+                    "size": r.range.len(),
+                })
+            })
+            .collect();
+        json!({
+            "chip": format!("{:?}", self.layout.chip),
+            "flash_size": self.layout.chip.flash_size(),
+            "offset": self.layout.application.start,
+            "eeprom_start": self.layout.eeprom.start,
+            "firmware_size": self.firmware,
+            "headroom": self.firmware.map(|len| self.layout.headroom(len)),
+            "regions": regions,
+        })
+    }
+}
+
+impl Display for Map {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let size = self.layout.chip.flash_size();
+        writeln!(
+            f,
+            "{:?}, {} bytes of flash, the firmware goes to {:#x}",
+            self.layout.chip, size, self.layout.application.start
+        )?;
+        writeln!(f, "[{}]", self.bar())?;
+        writeln!(
+            f,
+            " {:<width$}{:#x}",
+            "0x0",
+            size,
+            width = BAR_WIDTH - format!("{:#x}", size).len() + 1
+        )?;
+        for region in &self.regions {
+            writeln!(
+                f,
+                "  {} {:<10} {:#07x}..{:#07x} {:>6} bytes",
+                region.kind.symbol(),
+                region.kind.to_string(),
+                region.range.start,
+                region.range.end,
+                region.range.len()
+            )?;
+        }
+        if let Some(len) = self.firmware {
+            let bootloader = &self.layout.bootloader;
+            let overwritten = (self
+                .layout
+                .application
+                .start
+                .saturating_add(padded(len) as u32))
+            .min(bootloader.end)
+            .saturating_sub(self.layout.application.start.max(bootloader.start));
+            if overwritten > 0 {
+                writeln!(
+                    f,
+                    "The firmware overwrites {} bytes of the bootloader",
+                    overwritten
+                )?;
+            }
+            let headroom = self.layout.headroom(len);
+            let past_end = self.layout.application.start as i64 + padded(len) as i64 - size as i64;
+            if past_end > 0 {
+                writeln!(
+                    f,
+                    "The firmware runs through the EEPROM and {} bytes past the end of flash",
+                    past_end
+                )?;
+            } else if headroom < 0 {
+                writeln!(f, "The firmware runs {} bytes into the EEPROM", -headroom)?;
+            } else {
+                writeln!(f, "{} bytes left before the EEPROM", headroom)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The bootloader only writes whole blocks
+fn padded(len: usize) -> usize {
+    len.div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(map: &Map) -> Vec<(RegionKind, Range<u32>)> {
+        map.regions
+            .iter()
+            .map(|r| (r.kind, r.range.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn qmk() {
+        let map = Map::new(Layout::new(Sonix::SN32F260, 0x200), Some(0x1000));
+        assert_eq!(
+            kinds(&map),
+            [
+                (RegionKind::Bootloader, 0..0x200),
+                (RegionKind::Firmware, 0x200..0x1200),
+                (RegionKind::Free, 0x1200..0x7400),
+                (RegionKind::Eeprom, 0x7400..0x7800),
+            ]
+        );
+    }
+
+    #[test]
+    fn firmware_over_the_bootloader() {
+        let map = Map::new(Layout::new(Sonix::SN32F260, 0), Some(0x1000));
+        assert_eq!(
+            kinds(&map),
+            [
+                (RegionKind::Overlap, 0..0x200),
+                (RegionKind::Firmware, 0x200..0x1000),
+                (RegionKind::Free, 0x1000..0x7400),
+                (RegionKind::Eeprom, 0x7400..0x7800),
+            ]
+        );
+        assert!(map
+            .to_string()
+            .contains("The firmware overwrites 512 bytes of the bootloader"));
+
+        let map = Map::new(Layout::new(Sonix::SN32F260, 0), Some(0x100));
+        assert_eq!(
+            kinds(&map)[..2],
+            [
+                (RegionKind::Overlap, 0..0x100),
+                (RegionKind::Bootloader, 0x100..0x200),
+            ]
+        );
+    }
+}
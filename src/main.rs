@@ -1,23 +1,49 @@
+pub mod batch;
 pub mod cli;
+pub mod confirm;
 pub mod constants;
+pub mod database;
 pub mod devices;
+pub mod emulator;
 pub mod error;
 pub mod firmware;
 pub mod flash;
+pub mod layout;
+pub mod progress;
+pub mod reboot;
+pub mod selector;
+#[cfg(target_os = "linux")]
+pub mod simulator;
 pub mod traits;
+pub mod verify;
 use cli::Command;
 pub use error::Result;
+use std::time::Duration;
 
+use crate::constants::QMK_OFFSET_DEFAULT;
 use crate::flash::FlashingOptions;
 
-use self::cli::FirmwareCommand;
-use self::devices::{Bootloader, Devices};
+use self::cli::{FirmwareCommand, OutputFormat, RebootTarget};
+use self::devices::{Bootloader, Devices, Mode, Normal};
 use self::error::ErrorKind;
-use self::firmware::{Firmware, UnsafeFirmware};
+use self::firmware::{info, kind, lint, offset, Firmware, Format, UnsafeFirmware};
 
 fn main() -> Result<(), main_error::MainError> {
     let args = <cli::Args as clap::Parser>::parse();
+    // Only commands that look up keyboards in normal mode or name the vid:pid of a firmware need
+    // the device database
+    if matches!(
+        args.command,
+        Command::List { .. }
+            | Command::Firmware { .. }
+            | Command::Flash { .. }
+            | Command::Reboot { .. }
+            | Command::Simulate { .. }
+    ) {
+        devices::load_database()?;
+    }
     // dbg!(&args);
+    let family = args.uf2_family;
     match args.command {
         Command::List {
             verbose,
@@ -64,38 +90,465 @@ fn main() -> Result<(), main_error::MainError> {
             }
         }
         Command::Firmware { command } => match command {
-            FirmwareCommand::Check { path } => {
-                UnsafeFirmware::from(std::fs::File::open(path)?).check()?;
-                println!("The Firmware is valid");
+            FirmwareCommand::Check {
+                path,
+                chip,
+                keyboard,
+                format,
+                strict,
+                eeprom_size,
+            } => {
+                let chip = match (chip, keyboard) {
+                    (Some(chip), _) => Some(flash::Sonix::from(chip)),
+                    (None, Some(keyboard)) => {
+                        let devices = Devices::<Bootloader>::get()?;
+                        let d = devices.decide::<String>(Some(keyboard))?;
+                        Some(flash::Sonix::try_from(d.product_id())?)
+                    }
+                    (None, None) => None,
+                };
+                let firmware = UnsafeFirmware::open(&path, family)?;
+                let report = lint::lint(firmware.inner.get_ref(), firmware.base, chip, eeprom_size);
+                match format {
+                    OutputFormat::Json => println!("{}", report.to_json(strict)),
+                    OutputFormat::Text => {
+                        match firmware.format {
+                            Format::Elf => {
+                                print!("{}", firmware::elf::Elf::parse(&std::fs::read(&path)?)?)
+                            }
+                            Format::Uf2 => {
+                                let uf2 = firmware::uf2::parse(&std::fs::read(&path)?, family)?;
+                                if let Some(family) = uf2.family {
+                                    println!("UF2 family id: {:#010x}", family);
+                                }
+                            }
+                            _ => {}
+                        }
+                        if let Some(suffix) = &firmware.dfu {
+                            println!(
+                                "DFU suffix for {:04x}:{:04x} release {:x}, CRC {:#010x}",
+                                suffix.vendor_id, suffix.product_id, suffix.release, suffix.crc
+                            );
+                        }
+                        if let Some(base) = firmware.base {
+                            let chips = flash::Sonix::all()
+                                .into_iter()
+                                .filter(|chip| chip.offset() == base)
+                                .map(|chip| format!("{:?}", chip))
+                                .collect::<Vec<_>>();
+                            if chips.is_empty() {
+                                println!(
+                                    "The Firmware starts at {:#x}, no chip uses that offset",
+                                    base
+                                );
+                            } else {
+                                println!(
+                                    "The Firmware starts at {:#x} ({})",
+                                    base,
+                                    chips.join(", ")
+                                );
+                            }
+                        }
+                        print!("{}", report);
+                        if report.passed(strict) {
+                            println!("The Firmware is valid");
+                        }
+                    }
+                }
+                report.check(strict)?;
+            }
+            FirmwareCommand::Info { path, format } => {
+                let firmware = UnsafeFirmware::open(&path, family)?;
+                let info =
+                    info::Info::new(firmware.inner.get_ref(), firmware.format, firmware.base);
+                match format {
+                    OutputFormat::Json => println!("{}", info.to_json()),
+                    OutputFormat::Text => print!("{}", info),
+                }
             }
         },
         Command::Flash {
             firmware,
             keyboard,
             offset,
+            jumploader,
+            force_offset,
+            overwrite_bootloader,
+            wipe_eeprom,
+            eeprom_size,
+            all,
+            method,
+            verify,
+            expect,
+            verify_timeout,
         } => {
+            if all {
+                let mut firmware = UnsafeFirmware::open(&firmware, family)?;
+                // Fail once here instead of once per device
+                kind::refuse_dump(firmware.inner.get_ref())?;
+                firmware.check()?;
+                let devices = Devices::<Bootloader>::get()?;
+                let targets = match keyboard {
+                    Some(keyboard) => devices.select(&keyboard.parse()?),
+                    None => devices.devices.iter().collect(),
+                };
+                if targets.is_empty() {
+                    return Err(ErrorKind::NoDevicesFound.into());
+                }
+                if eeprom_size.is_none() {
+                    let mut chips = targets
+                        .iter()
+                        .filter_map(|d| flash::Sonix::try_from(d.product_id()).ok())
+                        .collect::<Vec<_>>();
+                    chips.sort_by_key(|chip| chip.pid());
+                    chips.dedup();
+                    chips.into_iter().for_each(warn_unknown_eeprom_size);
+                }
+                let options = batch::BatchOptions {
+                    offset,
+                    eeprom_size,
+                    force_offset,
+                    overwrite_bootloader,
+                    wipe_eeprom,
+                };
+                if overwrite_bootloader {
+                    // Devices whose options fail here fail the same way once flashing starts
+                    let (kind, len) = (kind::classify(firmware.inner.get_ref()), firmware.len()?);
+                    let overwritten = targets
+                        .iter()
+                        .filter(|d| {
+                            batch::options(d, kind, firmware.base, options)
+                                .is_ok_and(|o| o.check_protected(len).is_err())
+                        })
+                        .map(|d| devices.label(d))
+                        .collect::<Vec<_>>();
+                    if !overwritten.is_empty() {
+                        eprintln!("{}", confirm::BOOTLOADER_RISK);
+                        confirm::confirm(&format!(
+                            "The bootloader of {} will be overwritten.",
+                            overwritten.join(", ")
+                        ))?;
+                    }
+                }
+                let outcomes = batch::flash_all(&devices, &targets, &firmware, options)?;
+                batch::print_summary(&outcomes);
+                let failed = outcomes.iter().filter(|o| o.result.is_err()).count();
+                if failed > 0 {
+                    return Err(ErrorKind::BatchFailed(failed).into());
+                }
+                return Ok(());
+            }
+
+            // Check the firmware before touching a keyboard that might be in normal mode
+            let mut firmware = UnsafeFirmware::open(firmware, family)?;
+            let dfu = firmware.dfu.clone();
+            let kind = kind::refuse_dump(firmware.inner.get_ref())?;
+            let usb = firmware::usb::Usb::scan(firmware.inner.get_ref());
+            println!("Flashing {}", kind);
+            firmware.check()?;
+            let jumploader = match jumploader {
+                Some(path) => {
+                    let jumploader = UnsafeFirmware::open(&path, family)?;
+                    Some(
+                        firmware::jumploader::checked(jumploader)
+                            .ok_or_else(|| ErrorKind::NotAJumploader(path.display().to_string()))?,
+                    )
+                }
+                None => None,
+            };
+
             let devices = Devices::<Bootloader>::get()?;
-            let d = devices.decide::<String>(keyboard)?;
+            let (d, normal) = match devices.decide::<&str>(keyboard.as_deref()) {
+                Ok(d) => {
+                    // The suffix names the keyboard, which isn't known from its bootloader
+                    if let Some(Err(e)) = dfu
+                        .as_ref()
+                        .map(|suffix| suffix.check(&[(d.vendor_id(), d.product_id())]))
+                    {
+                        eprintln!("Warning: {}", e.kind);
+                    }
+                    (d.clone(), None)
+                }
+                Err(e)
+                    if matches!(
+                        e.kind,
+                        ErrorKind::NoDevicesFound | ErrorKind::DeviceNotFound
+                    ) =>
+                {
+                    // Maybe the keyboard is still in normal mode
+                    let normal = Devices::<Normal>::get()?;
+                    let n = normal.decide::<&str>(keyboard.as_deref())?;
+                    let identity = (n.vendor_id(), n.product_id());
+                    if let Some(suffix) = &dfu {
+                        suffix.check(&[identity])?;
+                    }
+                    println!(
+                        "Rebooting {:04x}:{:04x} into bootloader mode",
+                        identity.0, identity.1
+                    );
+                    let d = verify::enter_bootloader(
+                        n,
+                        devices::reboot_method(method, n).sequences(),
+                        verify::ENTER_BOOTLOADER_TIMEOUT,
+                    )?;
+                    (d, Some(identity))
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if let (Some(current), Some(new)) = (normal, usb.vid_pid()) {
+                if current != new {
+                    eprintln!(
+                        "Warning: the keyboard is {:04x}:{:04x} but the firmware makes it {:04x}:{:04x}, \
+                         make sure the firmware is for this keyboard",
+                        current.0, current.1, new.0, new.1
+                    );
+                }
+            }
             println!("device: {:#?}", d);
 
-            let firmware =
-                Firmware::try_from(UnsafeFirmware::from(std::fs::File::open(firmware)?))?;
+            let mut keyboard = devices::Keyboard::<Bootloader>::connect(&d)?;
 
-            let mut keyboard = devices::Keyboard::<Bootloader>::connect(d)?;
+            let mut options = FlashingOptions::try_from(&d)?;
+            let entry = normal.and_then(|identity| Normal::devices().get(&identity));
+            if eeprom_size.is_none() && normal.is_none() {
+                warn_unknown_eeprom_size(options.device_type);
+            }
+            if let Some((entry, chip)) =
+                entry.and_then(|entry| entry.chip.map(|chip| (entry, chip)))
+            {
+                if chip != options.device_type {
+                    eprintln!(
+                        "Warning: the device database says {} has a {:?} but its bootloader is a {:?}",
+                        entry.name, chip, options.device_type
+                    );
+                }
+            }
+            // With a jumploader in front the application always goes right after it
+            let offset = offset.or(jumploader.as_ref().map(|_| QMK_OFFSET_DEFAULT));
+            options
+                .with_offset(offset)
+                .with_kind(kind)
+                .with_eeprom_size(eeprom_size.or(entry.and_then(|entry| entry.eeprom_size)))
+                .with_wipe_eeprom(wipe_eeprom)
+                .with_base(firmware.base)?;
+            let inference = offset::infer(firmware.inner.get_ref());
+            match options.check_inferred(&inference) {
+                Err(e) if force_offset => eprintln!("Warning: {}", e.kind),
+                Err(e) => {
+                    eprintln!(
+                        "Use --offset to pick the right offset or --force-offset to flash anyway"
+                    );
+                    return Err(e.into());
+                }
+                Ok(()) => {}
+            }
+            if kind == kind::Kind::Stock && options.check_protected(firmware.len()?).is_err() {
+                eprintln!(
+                    "Stock firmware on the {:?} starts inside the bootloader, restoring it always \
+                     needs --overwrite-bootloader",
+                    options.device_type
+                );
+            }
+            guard_protected(&options, firmware.len()?, overwrite_bootloader)?;
+            options.check_eeprom(firmware.len()?)?;
+            if wipe_eeprom {
+                println!(
+                    "Erasing the emulated EEPROM at {:#x}, the keymap and VIA settings are reset",
+                    options.layout()?.eeprom.start
+                );
+            }
+            if jumploader.is_none()
+                && options.device_type == flash::Sonix::SN32F260
+                && inference.base() == Some(QMK_OFFSET_DEFAULT)
+            {
+                println!(
+                    "Note: QMK at {:#x} is started by a jumploader at 0x0, if the keyboard doesn't \
+                     have one yet pass --jumploader to flash it in the same run",
+                    QMK_OFFSET_DEFAULT
+                );
+            }
+            let firmware = Firmware::try_from((firmware, &options))?;
+            let mut progress = progress::ProgressBar::default();
+            match jumploader {
+                Some(jumploader) => {
+                    let mut jumploader_options = FlashingOptions::try_from(&d)?;
+                    jumploader_options
+                        .with_offset(Some(0))
+                        .with_base(jumploader.base)?;
+                    let inference = offset::infer(jumploader.inner.get_ref());
+                    match jumploader_options.check_inferred(&inference) {
+                        Err(e) if force_offset => eprintln!("Warning: {}", e.kind),
+                        result => result?,
+                    }
+                    guard_protected(&jumploader_options, jumploader.len()?, overwrite_bootloader)?;
+                    // The application goes first, a jumploader without one would jump into
+                    // erased flash
+                    keyboard.write_with(firmware, options, &mut progress)?;
+                    keyboard.flash_with(jumploader, jumploader_options, &mut progress)?;
+                }
+                None => keyboard.flash_with(firmware, options, &mut progress)?,
+            }
 
-            let mut options = FlashingOptions::try_from(d)?;
-            options.with_offset(offset);
-            keyboard.flash(firmware, options)?;
+            if verify {
+                // The open device holds on to the hidapi instance which has to be gone before
+                // enumerating again
+                drop(keyboard);
+                let expect = expect
+                    .as_deref()
+                    .map(devices::parse_vid_pid)
+                    .transpose()?
+                    .or(usb.vid_pid());
+                let origin = verify::Origin::from(&d);
+                match verify::verify(&origin, expect, Duration::from_secs(verify_timeout)) {
+                    Ok(device) => println!(
+                        "The keyboard came back as {:04x}:{:04x} {} (release {:x})",
+                        device.vendor_id(),
+                        device.product_id(),
+                        device.product_string().unwrap_or("Unknown"),
+                        device.release_number()
+                    ),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        eprintln!("{}", verify::RECOVERY_STEPS);
+                        return Err(e.into());
+                    }
+                }
+            }
+        }
+        Command::Layout {
+            firmware,
+            chip,
+            offset,
+            eeprom_size,
+            format,
+        } => {
+            let firmware = firmware
+                .map(|path| UnsafeFirmware::open(path, family))
+                .transpose()?;
+            let kind = firmware
+                .as_ref()
+                .map(|f| kind::classify(f.inner.get_ref()))
+                .unwrap_or(kind::Kind::Qmk);
+            let chips = match chip {
+                Some(chip) => vec![flash::Sonix::from(chip)],
+                None => flash::Sonix::all().to_vec(),
+            };
+            let mut maps = Vec::new();
+            for chip in chips {
+                let offset = offset
+                    .or(firmware.as_ref().and_then(|f| f.base))
+                    .unwrap_or(kind.offset(chip));
+                let mut layout = layout::Layout::new(chip, offset);
+                if let Some(size) = eeprom_size {
+                    layout = layout.with_eeprom_size(size)?;
+                }
+                let len = firmware.as_ref().map(|f| f.len()).transpose()?;
+                maps.push(layout::Map::new(layout, len));
+            }
+            match format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    serde_json::Value::Array(maps.iter().map(layout::Map::to_json).collect())
+                ),
+                OutputFormat::Text => {
+                    for (i, map) in maps.iter().enumerate() {
+                        if i > 0 {
+                            println!();
+                        }
+                        print!("{}", map);
+                    }
+                }
+            }
         }
         Command::Reboot {
             keyboard,
-            bootloader: _,
+            to,
+            method,
+        } => match to {
+            RebootTarget::Normal => {
+                let devices = Devices::<Bootloader>::get()?;
+                let d = devices.decide::<String>(keyboard)?;
+                let mut keyboard = devices::Keyboard::<Bootloader>::connect(d)?;
+                keyboard.reboot()?;
+            }
+            RebootTarget::Bootloader => {
+                let devices = Devices::<Normal>::get()?;
+                let d = devices.decide::<String>(keyboard)?;
+                let b = verify::enter_bootloader(
+                    d,
+                    devices::reboot_method(method, d).sequences(),
+                    verify::ENTER_BOOTLOADER_TIMEOUT,
+                )?;
+                println!(
+                    "The keyboard is in bootloader mode as {:04x}:{:04x}",
+                    b.vendor_id(),
+                    b.product_id()
+                );
+            }
+        },
+        Command::Simulate {
+            chip,
+            device,
+            normal,
+            dump,
         } => {
-            let devices = Devices::<Bootloader>::get()?;
-            let d = devices.decide::<String>(keyboard)?;
-            let mut keyboard = devices::Keyboard::<Bootloader>::connect(d)?;
-            keyboard.reboot()?;
+            #[cfg(target_os = "linux")]
+            {
+                let device = match device {
+                    Some(device) => devices::parse_vid_pid(&device)?,
+                    None => simulator::DEFAULT_NORMAL_DEVICE,
+                };
+                simulator::Simulator::new(chip.into(), device, normal)?
+                    .with_dump(dump)
+                    .run()?;
+            }
+            #[cfg(not(target_os = "linux"))]
+            {
+                let _ = (chip, device, normal, dump);
+                return Err(ErrorKind::Unsupported("simulate").into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keyboards in bootloader mode can't be looked up in the device database, so its EEPROM size
+/// doesn't apply to them
+fn warn_unknown_eeprom_size(chip: flash::Sonix) {
+    let entries = devices::custom_eeprom_sizes(chip);
+    if entries.is_empty() {
+        return;
+    }
+    eprintln!(
+        "Warning: the keyboard was already in bootloader mode, so the {:?} default EEPROM of {:#x} \
+         bytes is assumed. The device database lists other sizes for:",
+        chip,
+        chip.eeprom_size()
+    );
+    for entry in entries {
+        eprintln!(
+            "  {}: {:#x} bytes",
+            entry.name,
+            entry.eeprom_size.unwrap_or_default()
+        );
+    }
+    eprintln!("Pass --eeprom-size if the keyboard is one of them");
+}
+
+/// Refuse to write into the protected region of the chip unless the user insists
+fn guard_protected(
+    options: &FlashingOptions,
+    len: usize,
+    overwrite_bootloader: bool,
+) -> Result<()> {
+    if let Err(e) = options.check_protected(len) {
+        eprintln!("{}", e.kind);
+        if !overwrite_bootloader {
+            eprintln!("Check the --offset, only --overwrite-bootloader allows this");
+            return Err(e);
         }
+        eprintln!("{}", confirm::BOOTLOADER_RISK);
+        confirm::confirm("Overwrite the bootloader?")?;
     }
     Ok(())
 }
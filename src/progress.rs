@@ -0,0 +1,129 @@
+use std::io::Write;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+/// Something that happened while flashing a keyboard
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// `CMD_INIT` was acknowledged
+    Init,
+    /// `CMD_PREPARE` was acknowledged
+    Prepare { offset: u32, blocks: u32 },
+    /// A 64 byte chunk was sent
+    Chunk(Progress),
+    /// `CMD_REBOOT` was sent
+    Reboot,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    /// The number of the chunk that was just sent, starting at 1
+    pub chunk: usize,
+    pub chunks: usize,
+    pub bytes_written: usize,
+    pub total_bytes: usize,
+    /// Time since the first chunk was sent
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Bytes per second
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes_written as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        let throughput = self.throughput();
+        if throughput > 0.0 {
+            let remaining = self.total_bytes.saturating_sub(self.bytes_written);
+            Some(Duration::from_secs_f64(remaining as f64 / throughput))
+        } else {
+            None
+        }
+    }
+
+    pub fn fraction(&self) -> f64 {
+        if self.chunks == 0 {
+            1.0
+        } else {
+            self.chunk as f64 / self.chunks as f64
+        }
+    }
+}
+
+/// Receives [`Event`]s from [`Keyboard::flash_with`](crate::devices::Keyboard::flash_with)
+pub trait Observer {
+    fn notify(&mut self, event: &Event);
+}
+
+/// Ignore all events
+impl Observer for () {
+    fn notify(&mut self, _event: &Event) {}
+}
+
+impl<F: FnMut(&Event)> Observer for F {
+    fn notify(&mut self, event: &Event) {
+        self(event)
+    }
+}
+
+/// Forward all events over a channel, a dropped receiver is ignored
+impl Observer for Sender<Event> {
+    fn notify(&mut self, event: &Event) {
+        let _ = self.send(event.clone());
+    }
+}
+
+/// Renders a progress bar on stderr
+#[derive(Debug)]
+pub struct ProgressBar {
+    width: usize,
+}
+
+impl Default for ProgressBar {
+    fn default() -> Self {
+        Self { width: 40 }
+    }
+}
+
+impl Observer for ProgressBar {
+    fn notify(&mut self, event: &Event) {
+        let mut stderr = std::io::stderr().lock();
+        let _ = match event {
+            Event::Init => writeln!(stderr, "Initialized the bootloader"),
+            Event::Prepare { offset, blocks } => {
+                writeln!(stderr, "Writing {} blocks at {:#x}", blocks, offset)
+            }
+            Event::Chunk(progress) => {
+                let filled = (progress.fraction() * self.width as f64) as usize;
+                let eta = progress
+                    .eta()
+                    .map(|eta| format!("{:.1}s", eta.as_secs_f64()))
+                    .unwrap_or_else(|| "?".into());
+                write!(
+                    stderr,
+                    "\r[{:<width$}] {}/{} {:.1} KiB/s ETA {:<8}",
+                    "#".repeat(filled),
+                    progress.chunk,
+                    progress.chunks,
+                    progress.throughput() / 1024.0,
+                    eta,
+                    width = self.width
+                )
+                .and_then(|_| {
+                    if progress.chunk == progress.chunks {
+                        writeln!(stderr)
+                    } else {
+                        stderr.flush()
+                    }
+                })
+            }
+            Event::Reboot => writeln!(stderr, "Rebooting the keyboard"),
+        };
+    }
+}
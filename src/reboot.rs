@@ -0,0 +1,26 @@
+/// A feature report that makes the firmware of a keyboard jump to the bootloader
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Sequence {
+    EVision,
+    Hfd,
+}
+
+impl Sequence {
+    /// Every known reboot sequence in the order they are tried
+    pub const ALL: [Sequence; 2] = [Sequence::EVision, Sequence::Hfd];
+
+    pub fn commands(&self) -> [u8; 8] {
+        let mut res = [0; 8];
+        match self {
+            Sequence::EVision => {
+                res[..4].copy_from_slice(&0x5AA555AA_u32.to_le_bytes());
+                res[4..].copy_from_slice(&0xCC3300FF_u32.to_le_bytes());
+            }
+            Sequence::Hfd => {
+                res[..4].copy_from_slice(&0x5A8942AA_u32.to_le_bytes());
+                res[4..].copy_from_slice(&0xCC6271FF_u32.to_le_bytes());
+            }
+        }
+        res
+    }
+}
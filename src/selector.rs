@@ -0,0 +1,112 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use hidapi::DeviceInfo;
+
+use crate::devices::{parse_vid_pid, port, Mode};
+use crate::error::{Error, ErrorKind};
+use crate::flash::Sonix;
+
+/// Picks one or more keyboards out of the connected devices
+///
+/// Parsed from one of
+/// - `vid:pid` every keyboard with that vid:pid
+/// - `vid:pid#N` the N-th keyboard with that vid:pid, as shown by `sflasher list`
+/// - `path=/dev/hidraw3` the hid device path
+/// - `serial=…` the usb serial number
+/// - `port=1-2.3` the usb bus and port the keyboard is plugged into
+/// - a chip name like `SN32F248B`, bootloaders of that chip and keyboards whose
+///   [`DeviceEntry::chip`](crate::devices::DeviceEntry::chip) it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    VidPid {
+        vendor_id: u16,
+        product_id: u16,
+        index: Option<usize>,
+    },
+    Path(String),
+    Serial(String),
+    Port(String),
+    Chip(Sonix),
+}
+
+impl Selector {
+    /// Whether the device matches, ignoring the index of [`Selector::VidPid`]
+    pub fn matches<T: Mode>(&self, info: &DeviceInfo) -> bool {
+        match self {
+            Selector::VidPid {
+                vendor_id,
+                product_id,
+                ..
+            } => info.vendor_id() == *vendor_id && info.product_id() == *product_id,
+            Selector::Path(path) => info.path().to_str() == Ok(path.as_str()),
+            Selector::Serial(serial) => info.serial_number() == Some(serial.as_str()),
+            Selector::Port(p) => port(info).as_deref() == Some(p.as_str()),
+            Selector::Chip(chip) => T::devices()
+                .get(&(info.vendor_id(), info.product_id()))
+                .and_then(|entry| entry.chip)
+                .is_some_and(|c| c == *chip),
+        }
+    }
+
+    pub fn index(&self) -> Option<usize> {
+        match self {
+            Selector::VidPid { index, .. } => *index,
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Selector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ErrorKind::InvalidIdentifier(s.into());
+        if let Some((key, value)) = s.split_once('=') {
+            if value.is_empty() {
+                return Err(invalid().into());
+            }
+            return match key {
+                "path" => Ok(Selector::Path(value.into())),
+                "serial" => Ok(Selector::Serial(value.into())),
+                "port" => Ok(Selector::Port(value.into())),
+                _ => Err(invalid().into()),
+            };
+        }
+        if s.contains(':') {
+            let (vid_pid, index) = match s.split_once('#') {
+                Some((vid_pid, index)) => (vid_pid, Some(index.parse()?)),
+                None => (s, None),
+            };
+            let (vendor_id, product_id) = parse_vid_pid(vid_pid)?;
+            return Ok(Selector::VidPid {
+                vendor_id,
+                product_id,
+                index,
+            });
+        }
+        Ok(Selector::Chip(s.parse().map_err(|_| invalid())?))
+    }
+}
+
+impl Display for Selector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Selector::VidPid {
+                vendor_id,
+                product_id,
+                index,
+            } => {
+                write!(f, "{:04x}:{:04x}", vendor_id, product_id)?;
+                if let Some(index) = index {
+                    write!(f, "#{}", index)?;
+                }
+                Ok(())
+            }
+            Selector::Path(path) => write!(f, "path={}", path),
+            Selector::Serial(serial) => write!(f, "serial={}", serial),
+            Selector::Port(port) => write!(f, "port={}", port),
+            Selector::Chip(chip) => write!(f, "{:?}", chip),
+        }
+    }
+}
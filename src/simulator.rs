@@ -0,0 +1,236 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::constants::SONIX_VID;
+use crate::devices::{Bootloader, Mode, Normal};
+use crate::emulator::Emulator;
+use crate::flash::Sonix;
+use crate::reboot::Sequence;
+use crate::traits::transport::Transport;
+use crate::Result;
+
+// Event types and request layouts from linux/uhid.h
+const UHID_DESTROY: u32 = 1;
+const UHID_GET_REPORT: u32 = 9;
+const UHID_GET_REPORT_REPLY: u32 = 10;
+const UHID_CREATE2: u32 = 11;
+const UHID_SET_REPORT: u32 = 13;
+const UHID_SET_REPORT_REPLY: u32 = 14;
+const UHID_FEATURE_REPORT: u8 = 0;
+const UHID_EVENT_SIZE: usize = 4376;
+const BUS_USB: u16 = 0x03;
+const EIO: u16 = 5;
+
+/// The vid:pid used in normal mode when none is given
+pub const DEFAULT_NORMAL_DEVICE: (u16, u16) = (0x0c45, 0x652f);
+/// How long the keyboard stays gone while it reboots
+const REENUMERATE_DELAY: Duration = Duration::from_millis(500);
+
+/// A vendor defined collection with a single 64 byte feature report and no report id
+const REPORT_DESCRIPTOR: [u8; 21] = [
+    0x06, 0x00, 0xFF, // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01, // Usage (0x01)
+    0xA1, 0x01, // Collection (Application)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x40, //   Report Count (64)
+    0x09, 0x01, //   Usage (0x01)
+    0xB1, 0x02, //   Feature (Data,Var,Abs)
+    0xC0, // End Collection
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Normal,
+    Bootloader,
+}
+
+/// A virtual keyboard registered through `/dev/uhid`
+///
+/// In bootloader mode every feature report is handled by an [`Emulator`]. In normal mode the
+/// keyboard only listens for the EVision and HFD reboot sequences. Rebooting in either direction
+/// destroys the hid device and creates it again with the vid:pid of the other mode.
+pub struct Simulator {
+    uhid: File,
+    normal: (u16, u16),
+    emulator: Emulator,
+    state: State,
+    dump: Option<PathBuf>,
+}
+
+impl Simulator {
+    pub fn new(chip: Sonix, normal: (u16, u16), start_in_normal: bool) -> Result<Self> {
+        let uhid = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/uhid")?;
+        Ok(Self {
+            uhid,
+            normal,
+            emulator: Emulator::new(chip),
+            state: if start_in_normal {
+                State::Normal
+            } else {
+                State::Bootloader
+            },
+            dump: None,
+        })
+    }
+
+    pub fn with_dump(&mut self, dump: Option<PathBuf>) -> &mut Self {
+        self.dump = dump;
+        self
+    }
+
+    /// Create the device and serve requests until the process is killed
+    pub fn run(&mut self) -> Result<()> {
+        self.create()?;
+        let mut event = [0u8; UHID_EVENT_SIZE];
+        loop {
+            // Every read returns exactly one event
+            if self.uhid.read(&mut event)? == 0 {
+                return Ok(());
+            }
+            match u32_at(&event, 0) {
+                UHID_GET_REPORT => self.get_report(&event)?,
+                UHID_SET_REPORT => self.set_report(&event)?,
+                // UHID_START, UHID_STOP, UHID_OPEN, UHID_CLOSE and output reports need no answer
+                _ => {}
+            }
+        }
+    }
+
+    fn identity(&self) -> (u16, u16, String) {
+        match self.state {
+            State::Normal => {
+                let name = Normal::devices()
+                    .get(&self.normal)
+                    .map_or("Unknown", |entry| entry.name.as_str());
+                (self.normal.0, self.normal.1, name.to_string())
+            }
+            State::Bootloader => {
+                let pid = self.emulator.chip().pid();
+                let name = Bootloader::devices()
+                    .get(&(SONIX_VID, pid))
+                    .map_or("Unknown", |entry| entry.name.as_str());
+                (SONIX_VID, pid, name.to_string())
+            }
+        }
+    }
+
+    fn create(&mut self) -> Result<()> {
+        let (vid, pid, name) = self.identity();
+        let mut event = [0u8; UHID_EVENT_SIZE];
+        put(&mut event, 0, &UHID_CREATE2.to_le_bytes());
+        put_str(&mut event[4..132], &format!("sflasher simulated {}", name));
+        put_str(&mut event[132..196], "sflasher-sim");
+        put_str(&mut event[196..260], "sflasher-sim");
+        put(
+            &mut event,
+            260,
+            &(REPORT_DESCRIPTOR.len() as u16).to_le_bytes(),
+        );
+        put(&mut event, 262, &BUS_USB.to_le_bytes());
+        put(&mut event, 264, &(vid as u32).to_le_bytes());
+        put(&mut event, 268, &(pid as u32).to_le_bytes());
+        put(&mut event, 280, &REPORT_DESCRIPTOR);
+        self.uhid.write_all(&event)?;
+        println!("Created {}: {:04x}:{:04x}", name, vid, pid);
+        Ok(())
+    }
+
+    fn destroy(&mut self) -> Result<()> {
+        let mut event = [0u8; UHID_EVENT_SIZE];
+        put(&mut event, 0, &UHID_DESTROY.to_le_bytes());
+        self.uhid.write_all(&event)?;
+        Ok(())
+    }
+
+    fn get_report(&mut self, request: &[u8]) -> Result<()> {
+        let id = u32_at(request, 4);
+        let rtype = request[9];
+        let mut data = [0u8; 65];
+        let (err, size) = match (rtype, self.state) {
+            (UHID_FEATURE_REPORT, State::Bootloader) => {
+                (0, self.emulator.get_feature_report(&mut data)?)
+            }
+            (UHID_FEATURE_REPORT, State::Normal) => (0, data.len()),
+            _ => (EIO, 0),
+        };
+        let mut event = [0u8; UHID_EVENT_SIZE];
+        put(&mut event, 0, &UHID_GET_REPORT_REPLY.to_le_bytes());
+        put(&mut event, 4, &id.to_le_bytes());
+        put(&mut event, 8, &err.to_le_bytes());
+        put(&mut event, 10, &(size as u16).to_le_bytes());
+        put(&mut event, 12, &data[..size]);
+        self.uhid.write_all(&event)?;
+        Ok(())
+    }
+
+    fn set_report(&mut self, request: &[u8]) -> Result<()> {
+        let id = u32_at(request, 4);
+        let rtype = request[9];
+        let size = (u16::from_le_bytes([request[10], request[11]]) as usize).min(65);
+        // The data still starts with the report id, just like Transport::send_feature_report
+        let data = request[12..12 + size].to_vec();
+
+        let mut event = [0u8; UHID_EVENT_SIZE];
+        put(&mut event, 0, &UHID_SET_REPORT_REPLY.to_le_bytes());
+        put(&mut event, 4, &id.to_le_bytes());
+        let err = if rtype == UHID_FEATURE_REPORT { 0 } else { EIO };
+        put(&mut event, 8, &err.to_le_bytes());
+        self.uhid.write_all(&event)?;
+        if err != 0 {
+            return Ok(());
+        }
+
+        match self.state {
+            State::Bootloader => {
+                self.emulator.send_feature_report(&data)?;
+                if self.emulator.rebooted() {
+                    if let Some(dump) = &self.dump {
+                        std::fs::write(dump, self.emulator.flash())?;
+                        println!("Wrote the flash contents to {}", dump.display());
+                    }
+                    self.reenumerate(State::Normal)?;
+                }
+            }
+            State::Normal => {
+                let report = data.get(1..9).unwrap_or_default();
+                for method in Sequence::ALL {
+                    if report == method.commands() {
+                        println!("Received the {:?} reboot sequence", method);
+                        self.emulator.reset();
+                        self.reenumerate(State::Bootloader)?;
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn reenumerate(&mut self, state: State) -> Result<()> {
+        self.destroy()?;
+        std::thread::sleep(REENUMERATE_DELAY);
+        self.state = state;
+        self.create()
+    }
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+fn put(buf: &mut [u8], at: usize, data: &[u8]) {
+    buf[at..at + data.len()].copy_from_slice(data);
+}
+
+/// Copy a string into a fixed size, nul terminated field
+fn put_str(field: &mut [u8], s: &str) {
+    let len = s.len().min(field.len() - 1);
+    field[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
@@ -2,3 +2,4 @@ pub mod buffer;
 pub mod empty;
 pub mod hex;
 pub mod len;
+pub mod transport;
@@ -36,6 +36,12 @@ impl Len<usize> for String {
     }
 }
 
+impl<T: Len<U> + ?Sized, U> Len<U> for &T {
+    fn len(&self) -> U {
+        (**self).len()
+    }
+}
+
 impl<T: Len<U>, U> Len<U> for BufReader<T> {
     fn len(&self) -> U {
         self.get_ref().len()
@@ -0,0 +1,59 @@
+use hidapi::HidDevice;
+
+use crate::error::ErrorKind;
+use crate::Result;
+
+/// A device that speaks hid feature reports
+///
+/// `send_feature_report` and `get_feature_report` work on raw buffers where the first byte is the
+/// report id, exactly like hidapi does. `set_feature` and `get_feature` add and strip that byte so
+/// callers only deal with the 64 byte payload.
+pub trait Transport {
+    fn send_feature_report(&mut self, data: &[u8]) -> Result<()>;
+    fn get_feature_report(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+    fn set_feature(&mut self, report: impl AsRef<[u8]>) -> Result<()> {
+        let report = report.as_ref();
+        if report.len() > 64 {
+            return Err(ErrorKind::InvalidReportLength(report.len()).into());
+        }
+        let mut buf = [0u8; 65];
+        // add 00 at start for hidapi report id (No clue what this does)
+        // TODO: Figure out what the report id does
+        // max(report.len()) is 64, so this is safe
+        // since the buffer is 65 and the max index is 64
+        buf[1..=report.len()].copy_from_slice(report);
+        self.send_feature_report(&buf)
+    }
+
+    // # strip 00 at start for hidapi report id
+    // return dev.get_feature_report(0, RESPONSE_LEN + 1)[1:]
+    fn get_feature(&mut self) -> Result<[u8; 64]> {
+        // The report id is in buf[0] and the data is in buf[1..]
+        let mut buf = [0u8; 65];
+        self.get_feature_report(&mut buf)?;
+        let [_report, data @ ..] = buf;
+        Ok(data)
+    }
+}
+
+impl Transport for HidDevice {
+    fn send_feature_report(&mut self, data: &[u8]) -> Result<()> {
+        HidDevice::send_feature_report(self, data)?;
+        Ok(())
+    }
+
+    fn get_feature_report(&mut self, buf: &mut [u8]) -> Result<usize> {
+        Ok(HidDevice::get_feature_report(self, buf)?)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn send_feature_report(&mut self, data: &[u8]) -> Result<()> {
+        (**self).send_feature_report(data)
+    }
+
+    fn get_feature_report(&mut self, buf: &mut [u8]) -> Result<usize> {
+        (**self).get_feature_report(buf)
+    }
+}
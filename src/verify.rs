@@ -0,0 +1,134 @@
+use std::time::{Duration, Instant};
+
+use hidapi::{DeviceInfo, HidApi};
+
+use crate::devices::{port, Bootloader, Keyboard, Mode, Normal};
+use crate::error::ErrorKind;
+use crate::reboot::Sequence;
+use crate::Result;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How long to wait for the bootloader after sending a reboot sequence
+pub const ENTER_BOOTLOADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const RECOVERY_STEPS: &str = "\
+Try the following to recover the keyboard:
+  1. Unplug the keyboard and plug it back in.
+  2. If it shows up in `sflasher list --bootloader`, flash it again and double check the --offset
+     and that the firmware was built for this chip.
+  3. If nothing shows up, hold the bootloader key combination of the board (or short the boot
+     pads) while plugging it in and flash a known good firmware.";
+
+/// Where the keyboard was before it rebooted
+#[derive(Debug, Clone)]
+pub struct Origin {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub port: Option<String>,
+}
+
+impl From<&DeviceInfo> for Origin {
+    fn from(info: &DeviceInfo) -> Self {
+        Self {
+            vendor_id: info.vendor_id(),
+            product_id: info.product_id(),
+            port: port(info),
+        }
+    }
+}
+
+impl Origin {
+    fn same_port(&self, info: &DeviceInfo) -> bool {
+        match &self.port {
+            Some(expected) => port(info).as_ref() == Some(expected),
+            // Without topology information any device counts
+            None => true,
+        }
+    }
+}
+
+/// Poll the hid devices until `f` returns something or `timeout` runs out
+///
+/// No other [`HidApi`] or open device may be alive while this runs.
+pub fn poll<T>(timeout: Duration, mut f: impl FnMut(&HidApi) -> Option<T>) -> Result<Option<T>> {
+    let mut api = HidApi::new()?;
+    let start = Instant::now();
+    loop {
+        if let Some(found) = f(&api) {
+            return Ok(Some(found));
+        }
+        if start.elapsed() >= timeout {
+            return Ok(None);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+        api.refresh_devices()?;
+    }
+}
+
+/// Wait for the keyboard at `origin` to go away and come back in normal mode
+///
+/// `expect` overrides the list of known normal mode devices.
+pub fn verify(
+    origin: &Origin,
+    expect: Option<(u16, u16)>,
+    timeout: Duration,
+) -> Result<DeviceInfo> {
+    let start = Instant::now();
+    let gone = poll(timeout, |api| {
+        let present = api.device_list().any(|d| {
+            d.vendor_id() == origin.vendor_id
+                && d.product_id() == origin.product_id
+                && origin.same_port(d)
+        });
+        (!present).then_some(())
+    })?;
+    if gone.is_none() {
+        return Err(ErrorKind::StuckInBootloader.into());
+    }
+
+    let device = poll(timeout.saturating_sub(start.elapsed()), |api| {
+        api.device_list()
+            .find(|d| {
+                let key = (d.vendor_id(), d.product_id());
+                let known = match expect {
+                    Some(expect) => key == expect,
+                    None => Normal::devices().contains_key(&key),
+                };
+                known && origin.same_port(d)
+            })
+            .cloned()
+    })?;
+    Ok(device.ok_or(ErrorKind::DeviceDidNotComeBack)?)
+}
+
+/// Reboot a normal mode keyboard into its bootloader and wait for the bootloader to show up
+///
+/// Every method is tried in order until one of them works.
+pub fn enter_bootloader(
+    info: &DeviceInfo,
+    methods: &[Sequence],
+    timeout: Duration,
+) -> Result<DeviceInfo> {
+    let origin = Origin::from(info);
+    let mut last_error = None;
+    for method in methods {
+        // The keyboard may drop off the bus before acknowledging the report, so an error only
+        // matters if the bootloader never shows up
+        let sent = Keyboard::<Normal>::connect(info).and_then(|mut k| k.reboot(*method));
+        let device = poll(timeout, |api| {
+            api.device_list()
+                .find(|d| {
+                    Bootloader::devices().contains_key(&(d.vendor_id(), d.product_id()))
+                        && origin.same_port(d)
+                })
+                .cloned()
+        })?;
+        match (device, sent) {
+            (Some(device), _) => return Ok(device),
+            // The keyboard may not understand this sequence but the next one
+            (None, Err(e)) => last_error = Some(e),
+            (None, Ok(())) => {}
+        }
+    }
+    Err(last_error.unwrap_or_else(|| ErrorKind::FailedToEnterBootloader.into()))
+}